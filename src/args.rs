@@ -1,11 +1,17 @@
-use crate::error::{MyError, MyResult};
+use crate::{
+    codigo_uf,
+    error::{MyError, MyResult},
+    sigla_uf, Agrupamento, ClasseChave, Dedup, Emissao, Escopo, Formato, KeyFilter, OpcoesLista,
+    Operacao, Situacao, DEFAULT_CACHE_DIR,
+};
 use clap::{
     builder::{
         styling::{AnsiColor, Effects},
         Styles,
     },
-    Parser,
+    ArgGroup, Args, Parser, Subcommand, ValueEnum,
 };
+use encoding_rs::Encoding;
 use std::{fs, path::PathBuf};
 
 /// Custom Clap styling to mimic a beautiful colored help menu.
@@ -30,6 +36,52 @@ const APPLET_TEMPLATE: &str = "\
 {all-args}
 {after-help}";
 
+/// Parses a state abbreviation ("SP") or IBGE code ("35").
+fn parse_uf(text: &str) -> Result<u8, String> {
+    text.trim()
+        .parse()
+        .ok()
+        .filter(|&codigo| sigla_uf(codigo).is_some())
+        .or_else(|| codigo_uf(text))
+        .ok_or_else(|| format!("unknown state '{text}'"))
+}
+
+/// Parses a month ("2025-01" or "202501") into the AAMM of the keys ("2501").
+fn parse_mes(text: &str) -> Result<String, String> {
+    let digitos: String = text.chars().filter(|c| *c != '-' && *c != '/').collect();
+    let mes = digitos.get(4..).and_then(|mes| mes.parse::<u8>().ok());
+
+    match mes {
+        Some(1..=12) if digitos.len() == 6 && digitos.starts_with("20") => {
+            Ok(digitos[2..].to_string())
+        }
+        _ => Err(format!("'{text}' is not a month in the format aaaa-mm")),
+    }
+}
+
+/// Removes the punctuation of a CNPJ (or CNPJ root) with `len` characters.
+fn parse_cnpj_len(text: &str, len: usize) -> Result<String, String> {
+    let cnpj: String = text
+        .chars()
+        .filter(|c| !matches!(c, '.' | '/' | '-' | ' '))
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    if cnpj.len() == len && cnpj.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Ok(cnpj)
+    } else {
+        Err(format!("'{text}' does not have {len} characters"))
+    }
+}
+
+fn parse_cnpj(text: &str) -> Result<String, String> {
+    parse_cnpj_len(text, 14)
+}
+
+fn parse_cnpj_raiz(text: &str) -> Result<String, String> {
+    parse_cnpj_len(text, 8)
+}
+
 #[derive(Parser, Debug)]
 #[command(
     // Read from `Cargo.toml`
@@ -40,6 +92,12 @@ const APPLET_TEMPLATE: &str = "\
     styles=get_styles(),
 )]
 pub struct Arguments {
+    /// Instead of extracting keys to the output file: compare two sets of keys (diff),
+    /// reconcile the EFD keys with XML files or a portal list (reconcile),
+    /// or print totals of the EFD keys (stats).
+    #[command(subcommand)]
+    pub comando: Option<Comando>,
+
     /// Set the minimum depth to search for identical files.
     ///
     /// depth >= min_depth
@@ -60,10 +118,240 @@ pub struct Arguments {
     pub max_depth: usize,
 
     /// Set the SPED EFD txt file path, otherwise recursively search
-    /// for txt files in the current directory
+    /// for the files of the layout (PISCOFINS*.txt by default) in the current directory
     #[arg(short('p'), long("path"), required = false)]
     pub path: Option<PathBuf>,
 
+    /// Memory-map large files and scan them in parallel chunks.
+    ///
+    /// Files larger than the chunk size are split at newline boundaries,
+    /// so a single big EFD file is processed on all cores.
+    #[arg(short('m'), long("mmap"), default_value_t = false)]
+    pub mmap: bool,
+
+    /// Chunk size, in MiB, used with the --mmap option.
+    #[arg(
+        short('c'),
+        long("chunk_size"),
+        value_name = "MIB",
+        default_value_t = 64,
+        requires = "mmap"
+    )]
+    pub chunk_size: usize,
+
+    /// Also recognize keys written in groups of 4 characters
+    /// separated by spaces, dots or dashes ("3525 0612 3456 ...").
+    #[arg(long("formatadas"), default_value_t = false)]
+    pub formatadas: bool,
+
+    /// TOML file with the named fields of the records of the SPED layout
+    /// (see layouts/efd_contribuicoes.toml, embedded as the default).
+    ///
+    /// Maps the 0000 header, the 0150 participants and the records with a key
+    /// field (CHV_*) of other SPED text layouts, and the names of their files
+    /// (arquivos = ["SPEDECF*.txt"]).
+    #[arg(long("layout"), value_name = "ARQUIVO")]
+    pub layout: Option<PathBuf>,
+
+    /// Output file format.
+    #[arg(short('f'), long("formato"), value_enum, default_value_t = Formato::Texto)]
+    pub formato: Formato,
+
+    /// Output file.
+    ///
+    /// Default: efd-chaves_eletronicas.<extension of the format>
+    #[arg(short('o'), long("output"), value_name = "ARQUIVO", required = false)]
+    pub output: Option<PathBuf>,
+
+    /// Classes of 44-digit sequences to keep, separated by commas.
+    ///
+    /// chave-acesso: valid check digit, UF and model (NF-e, CT-e, ...);
+    /// boleto: bank payment slip barcode;
+    /// arrecadacao: collection barcode (starts with 8);
+    /// desconhecida: any other sequence.
+    #[arg(
+        long("classes"),
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = ClasseChave::value_variants().to_vec(),
+    )]
+    pub classes: Vec<ClasseChave>,
+
+    /// Keep only keys cited by C100/D100/C500 documents with these
+    /// types of operation (IND_OPER), separated by commas.
+    #[arg(long("operacao"), value_enum, value_delimiter = ',')]
+    pub operacoes: Vec<Operacao>,
+
+    /// Keep only keys cited by C100/D100/C500 documents with these
+    /// situations (COD_SIT), separated by commas.
+    ///
+    /// Example: --situacao regular,regular-extemporaneo
+    #[arg(long("situacao"), value_enum, value_delimiter = ',')]
+    pub situacoes: Vec<Situacao>,
+
+    /// Keep only keys of these document models, separated by commas.
+    ///
+    /// Example: --modelo 55,65
+    #[arg(long("modelo"), value_delimiter = ',')]
+    pub modelos: Vec<u8>,
+
+    /// Keep only keys issued in these states (abbreviation or IBGE code), separated by commas.
+    ///
+    /// Example: --uf SP,RJ
+    #[arg(long("uf"), value_delimiter = ',', value_parser = parse_uf)]
+    pub ufs: Vec<u8>,
+
+    /// Keep only keys issued from this month on (aaaa-mm).
+    #[arg(long("desde"), value_name = "AAAA-MM", value_parser = parse_mes)]
+    pub desde: Option<String>,
+
+    /// Keep only keys issued up to this month (aaaa-mm).
+    #[arg(long("ate"), value_name = "AAAA-MM", value_parser = parse_mes)]
+    pub ate: Option<String>,
+
+    /// Keep only keys of these issuer CNPJs (14 characters, punctuation allowed),
+    /// separated by commas.
+    #[arg(long("cnpj"), value_delimiter = ',', value_parser = parse_cnpj)]
+    pub cnpjs: Vec<String>,
+
+    /// Keep only keys whose issuer CNPJ has one of these roots (first 8 characters,
+    /// punctuation allowed), separated by commas.
+    ///
+    /// Example: --cnpj-raiz 12.345.678
+    #[arg(long("cnpj-raiz"), value_delimiter = ',', value_parser = parse_cnpj_raiz)]
+    pub cnpj_raizes: Vec<String>,
+
+    /// Keep only keys with these types of issue (tpEmis), separated by commas.
+    ///
+    /// 1: normal; 3: special regime of Nota Fiscal Fácil; contingency:
+    /// 2 FS-IA (NF-e, NFC-e; offline for other models), 4 EPEC, 5 FS-DA, 6 SVC-AN (NF-e, NFC-e),
+    /// 7 SVC-RS, 8 SVC-SP (CT-e), 9 offline NFC-e. Other codes are unknown for the model.
+    #[arg(long("tp-emis"), value_delimiter = ',')]
+    pub tp_emis: Vec<u8>,
+
+    /// Keep only keys issued by the declaring company or its branches (propria),
+    /// or by third parties (terceiros), comparing the issuer CNPJ root of the key
+    /// with the CNPJ of the 0000 record.
+    ///
+    /// Example, for credit review: --emissao terceiros --operacao entrada
+    #[arg(long("emissao"), value_enum)]
+    pub emissao: Option<Emissao>,
+
+    /// Scope of deduplication: which files a key must be unique across.
+    ///
+    /// execucao: the whole run; cnpj: the files of each declaring CNPJ;
+    /// arquivo: each file; nenhum: each file, counting the occurrences in the whole run
+    /// (see --contar).
+    #[arg(long("escopo"), value_enum, default_value_t = Escopo::Execucao)]
+    pub escopo: Escopo,
+
+    /// Count the files of the --escopo where each key was found (column ocorrencias
+    /// of the csv and ndjson formats).
+    ///
+    /// The keys are then written at the end of the run. Without this option,
+    /// the column is 1, except with --dedup externa, which counts while merging.
+    #[arg(long("contar"), default_value_t = false)]
+    pub contar: bool,
+
+    /// Strategy to remove keys found in more than one file (with --escopo execucao).
+    ///
+    /// memoria: keys are written as each file is processed;
+    /// externa: keys are sorted on disk and written at the end.
+    #[arg(long("dedup"), value_enum, default_value_t = Dedup::Memoria)]
+    pub dedup: Dedup,
+
+    /// Also write a CSV report of near-miss keys to this file.
+    ///
+    /// Reports runs of 42 to 46 digits, 44-character sequences with a wrong
+    /// check digit, and keys with an invalid UF or model, with the file, the line
+    /// and, when a single-digit fix gives a valid key, the suggested correction.
+    #[arg(long("diagnostico"), value_name = "ARQUIVO", required = false)]
+    pub diagnostico: Option<PathBuf>,
+
+    /// Also write a CSV report of C100/D100/C500 records whose key disagrees
+    /// with the other fields of the record to this file.
+    ///
+    /// Compares the model, series, number and AAMM (from DT_DOC) encoded in the key
+    /// and, for documents issued by third parties, the issuer CNPJ with the 0150 participant.
+    /// Blank fields are not compared. Only the documents kept by the filters are checked.
+    #[arg(long("conferencia"), value_name = "ARQUIVO", required = false)]
+    pub conferencia: Option<PathBuf>,
+
+    /// Also write a CSV report of the keys cited by C100/D100/C500 documents,
+    /// sorted by the name of the 0150 participant (supplier or customer).
+    #[arg(long("por-participante"), value_name = "ARQUIVO", required = false)]
+    pub por_participante: Option<PathBuf>,
+
+    /// Also write a CSV report with the first and last period (0000 record)
+    /// where each key was declared, and the number of periods and files.
+    #[arg(long("ciclo-de-vida"), value_name = "ARQUIVO", required = false)]
+    pub ciclo_de_vida: Option<PathBuf>,
+
+    /// Also write a CSV report of the access keys issued in contingency
+    /// (tpEmis: FS-IA, EPEC, FS-DA, SVC-AN, SVC-RS, SVC-SP, offline NFC-e) to this file,
+    /// with the type of issue, the month of issue and the first period where each was declared.
+    /// Keys with a type of issue unknown for their model follow, with the type desconhecido.
+    #[arg(long("contingencia"), value_name = "ARQUIVO", required = false)]
+    pub contingencia: Option<PathBuf>,
+
+    /// Also write a CSV report of the gaps and duplicates in the numbering of the
+    /// keys issued by the declaring company (see --emissao) to this file.
+    ///
+    /// Numbers are checked by issuer CNPJ, model, series and month of issue.
+    #[arg(long("numeracao"), value_name = "ARQUIVO", required = false)]
+    pub numeracao: Option<PathBuf>,
+
+    /// Directory with NF-e/CT-e event XMLs (procEventoNFe, ...), also inside ZIP files.
+    ///
+    /// Documents cancelled by an event (tpEvento 110111 or 110112) but declared in
+    /// C100/D100/C500 with a COD_SIT other than cancelled are written to --canceladas.
+    #[arg(long("eventos"), value_name = "DIR", requires = "canceladas")]
+    pub eventos: Option<PathBuf>,
+
+    /// CSV report of the cancelled documents declared as not cancelled (see --eventos).
+    #[arg(long("canceladas"), value_name = "ARQUIVO", requires = "eventos")]
+    pub canceladas: Option<PathBuf>,
+
+    /// In the --ciclo-de-vida report, list only the keys declared in two or more periods.
+    #[arg(long("duplicados-entre-periodos"), requires = "ciclo_de_vida")]
+    pub duplicados_entre_periodos: bool,
+
+    /// Cache directory, with the keys of each processed EFD file in its own entry.
+    ///
+    /// Files unchanged since the previous run (same size and modification time,
+    /// or same content hash) are loaded from the cache instead of parsed.
+    #[arg(long("cache"), value_name = "DIR", default_value = DEFAULT_CACHE_DIR)]
+    pub cache: PathBuf,
+
+    /// Do not read or write the cache.
+    #[arg(
+        long("no-cache"),
+        default_value_t = false,
+        conflicts_with = "rebuild_cache"
+    )]
+    pub no_cache: bool,
+
+    /// Ignore the entries of the cache: parse all files and replace their entries.
+    #[arg(long("rebuild-cache"), default_value_t = false)]
+    pub rebuild_cache: bool,
+
+    /// Keep running and extract keys from new or modified EFD files.
+    ///
+    /// Newly seen keys are appended to the output file (and the cache).
+    /// A file is processed once it contains the 9999 record
+    /// or once its size stays the same between two checks.
+    #[arg(short('w'), long("watch"), default_value_t = false)]
+    pub watch: bool,
+
+    /// Interval, in seconds, between two checks of the --watch option.
+    #[arg(
+        long("watch_interval"),
+        value_name = "SEGUNDOS",
+        default_value_t = 10,
+        requires = "watch"
+    )]
+    pub watch_interval: u64,
+
     /// Show total execution time
     #[arg(short('t'), long("time"), default_value_t = false)]
     pub time: bool,
@@ -73,14 +361,214 @@ pub struct Arguments {
     pub verbose: bool,
 }
 
+/// Commands that run instead of the extraction.
+#[derive(Subcommand, Debug)]
+pub enum Comando {
+    /// Compare the keys of two inputs (e.g. an original EFD and its rectifying version).
+    ///
+    /// Each input is a directory (searched recursively for EFD files),
+    /// an EFD file, or an output saved by a previous run (txt, csv or ndjson).
+    /// Writes a CSV with the keys only in A, only in B and in both.
+    Diff(DiffArgs),
+
+    /// Reconcile the keys of the EFD files (see --path) with a folder of
+    /// NF-e, NFC-e and CT-e XML files (also inside ZIP files), or with a list of
+    /// issued and received documents exported from a SEFAZ or Receita portal.
+    ///
+    /// Writes a CSV with the keys missing from the EFD and the EFD keys
+    /// absent from the XMLs or from the list.
+    ///
+    /// The key filters apply; --operacao, --situacao and --emissao only to the EFD keys.
+    Reconcile(ReconcileArgs),
+
+    /// Print totals of the unique keys of the EFD files (see --path):
+    /// by class, by document model, by issuer UF, by month of issue (AAMM)
+    /// and the issuers (CNPJ) with the most keys.
+    ///
+    /// The key filters apply: --classes, --operacao, --situacao, --modelo, --uf,
+    /// --desde, --ate, --cnpj, --cnpj-raiz, --tp-emis and --emissao.
+    Stats(StatsArgs),
+}
+
+/// Options of the `diff` command.
+#[derive(Args, Debug)]
+pub struct DiffArgs {
+    /// First input (A).
+    pub a: PathBuf,
+
+    /// Second input (B).
+    pub b: PathBuf,
+
+    /// Compare the keys separately for each CNPJ and/or period (record 0000),
+    /// separated by commas.
+    #[arg(long("por"), value_enum, value_delimiter = ',')]
+    pub por: Vec<Agrupamento>,
+
+    /// CSV file with the comparison.
+    #[arg(short('o'), long("output"), default_value = "efd-chaves_diff.csv")]
+    pub output: PathBuf,
+}
+
+/// Options of the `stats` command.
+#[derive(Args, Debug)]
+pub struct StatsArgs {
+    /// Number of issuers with the most keys to list.
+    #[arg(long("top"), default_value_t = 10)]
+    pub top: usize,
+
+    /// Also write the totals to this file: JSON (.json extension) or CSV.
+    #[arg(short('o'), long("output"), value_name = "ARQUIVO")]
+    pub output: Option<PathBuf>,
+}
+
+/// Options of the `reconcile` command.
+#[derive(Args, Debug)]
+#[command(group(ArgGroup::new("fonte").required(true).args(["xml", "contra"])))]
+pub struct ReconcileArgs {
+    /// Directory with the XML files (procNFe, procCTe, NFC-e), searched recursively.
+    #[arg(long("xml"), value_name = "DIR")]
+    pub xml: Option<PathBuf>,
+
+    /// List of documents (CSV, XLSX, XLS or ODS) downloaded from a SEFAZ portal
+    /// or from the Receita Federal, with one key per row.
+    #[arg(long("contra"), value_name = "ARQUIVO")]
+    pub contra: Option<PathBuf>,
+
+    /// Column of the --contra list with the keys: its header or its position (1-based).
+    ///
+    /// By default, the first column whose header contains "chave".
+    #[arg(long("coluna"), requires = "contra")]
+    pub coluna: Option<String>,
+
+    /// Field delimiter of a --contra CSV list.
+    #[arg(long("delimitador"), default_value_t = ';', requires = "contra")]
+    pub delimitador: char,
+
+    /// Encoding of a --contra CSV list (e.g. utf-8, windows-1252, iso-8859-1).
+    #[arg(
+        long("codificacao"),
+        default_value = "windows-1252",
+        requires = "contra"
+    )]
+    pub codificacao: String,
+
+    /// CSV file with the keys found in only one side.
+    #[arg(
+        short('o'),
+        long("output"),
+        default_value = "efd-chaves_conciliacao.csv"
+    )]
+    pub output: PathBuf,
+}
+
+impl ReconcileArgs {
+    /// How to read the --contra list.
+    pub fn opcoes_lista(&self) -> MyResult<OpcoesLista> {
+        let codificacao = Encoding::for_label(self.codificacao.as_bytes()).ok_or_else(|| {
+            MyError::InvalidOptions(format!("unknown encoding '{}'", self.codificacao))
+        })?;
+        let delimitador = u8::try_from(self.delimitador)
+            .ok()
+            .filter(u8::is_ascii)
+            .ok_or_else(|| {
+                MyError::InvalidOptions(format!(
+                    "the delimiter '{}' must be an ASCII character",
+                    self.delimitador
+                ))
+            })?;
+
+        Ok(OpcoesLista {
+            coluna: self.coluna.clone(),
+            delimitador,
+            codificacao,
+        })
+    }
+}
+
 impl Arguments {
     /// Build Arguments struct
     pub fn build() -> MyResult<Arguments> {
         let args: Arguments = Arguments::parse();
         args.validate_dir_path()?;
+        args.validate_options()?;
         Ok(args)
     }
 
+    /// Output file path: the --output option or the default name for the format.
+    pub fn output_path(&self) -> PathBuf {
+        self.output.clone().unwrap_or_else(|| {
+            PathBuf::from(format!(
+                "efd-chaves_eletronicas.{}",
+                self.formato.extension()
+            ))
+        })
+    }
+
+    /// Filter of the keys written to the outputs.
+    pub fn key_filter(&self) -> KeyFilter {
+        KeyFilter {
+            classes: self.classes.clone(),
+            operacoes: self.operacoes.clone(),
+            situacoes: self.situacoes.clone(),
+            modelos: self.modelos.clone(),
+            ufs: self.ufs.clone(),
+            desde: self.desde.clone(),
+            ate: self.ate.clone(),
+            cnpjs: self.cnpjs.clone(),
+            cnpj_raizes: self.cnpj_raizes.clone(),
+            tp_emis: self.tp_emis.clone(),
+            emissao: self.emissao,
+        }
+    }
+
+    /// Validate combinations of options
+    fn validate_options(&self) -> MyResult<()> {
+        if self.dedup == Dedup::Externa && self.escopo != Escopo::Execucao {
+            return Err(MyError::InvalidOptions(
+                "--dedup externa requires --escopo execucao".to_string(),
+            ));
+        }
+
+        if let (Some(desde), Some(ate)) = (&self.desde, &self.ate) {
+            if desde > ate {
+                return Err(MyError::InvalidOptions(
+                    "--desde must not be after --ate".to_string(),
+                ));
+            }
+        }
+
+        if self.contar && self.formato == Formato::Texto {
+            return Err(MyError::InvalidOptions(
+                "--contar requires --formato csv or ndjson".to_string(),
+            ));
+        }
+
+        if self.watch && self.dedup == Dedup::Externa {
+            return Err(MyError::InvalidOptions(
+                "--watch writes keys as files arrive and requires --dedup memoria".to_string(),
+            ));
+        }
+
+        // These reports are written at the end of the run, which never comes with --watch.
+        if self.watch {
+            let relatorios = [
+                ("--contar", self.contar),
+                ("--por-participante", self.por_participante.is_some()),
+                ("--ciclo-de-vida", self.ciclo_de_vida.is_some()),
+                ("--contingencia", self.contingencia.is_some()),
+                ("--numeracao", self.numeracao.is_some()),
+                ("--canceladas", self.canceladas.is_some()),
+            ];
+            if let Some((relatorio, _)) = relatorios.iter().find(|(_, usado)| *usado) {
+                return Err(MyError::InvalidOptions(format!(
+                    "{relatorio} is written at the end of the run and cannot be used with --watch"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
     /// Validate directory paths
     fn validate_dir_path(&self) -> MyResult<()> {
         let paths = [&self.path];
@@ -105,3 +593,47 @@ impl Arguments {
         Ok(())
     }
 }
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output args_tests
+#[cfg(test)]
+mod args_tests {
+    use super::*;
+    use crate::{ExtractionConfig, MIB};
+
+    #[test]
+    fn reports_written_at_the_end_are_rejected_with_watch() -> MyResult<()> {
+        let validate = |args: &[&str]| {
+            let args = Arguments::try_parse_from(args)
+                .map_err(|e| MyError::InvalidOptions(e.to_string()))?;
+            args.validate_options()
+        };
+
+        assert!(validate(&["efd", "--watch", "--ciclo-de-vida", "ciclo.csv"]).is_err());
+        assert!(validate(&["efd", "--watch", "--numeracao", "numeracao.csv"]).is_err());
+        assert!(validate(&["efd", "--watch", "-f", "csv"]).is_ok());
+        assert!(validate(&["efd", "--watch", "-f", "csv", "--contar"]).is_err());
+        assert!(validate(&["efd", "-f", "csv", "--contar"]).is_ok());
+        assert!(validate(&["efd", "--contar"]).is_err());
+        assert!(validate(&["efd", "--ciclo-de-vida", "ciclo.csv"]).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_size_that_overflows_is_rejected() -> MyResult<()> {
+        let build = |chunk_size: &str| {
+            let args = Arguments::try_parse_from(["efd", "--mmap", "-c", chunk_size])
+                .map_err(|e| MyError::InvalidOptions(e.to_string()))?;
+            ExtractionConfig::build(&args)
+        };
+
+        assert_eq!(build("2")?.mmap_chunk_size, Some(2 * MIB));
+        let too_large = usize::MAX.to_string();
+        assert!(matches!(build(&too_large), Err(MyError::InvalidOptions(_))));
+        Ok(())
+    }
+}
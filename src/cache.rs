@@ -0,0 +1,385 @@
+use crate::{
+    error::{MyError, MyResult},
+    EfdFileKeys,
+};
+use claudiofsr_lib::blake3_hash;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::UNIX_EPOCH,
+};
+use tempfile::NamedTempFile;
+
+/// Version of the cache entry format. Entries with another version are ignored.
+const CACHE_FORMAT_VERSION: u32 = 6;
+
+/// Default cache directory.
+pub const DEFAULT_CACHE_DIR: &str = "efd-chaves_cache";
+
+/// Size and modification time of a file, used as a cheap change detector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub mtime_secs: u64,
+    pub mtime_nanos: u32,
+}
+
+impl FileStamp {
+    /// Reads the size and the modification time of a file.
+    pub fn read(path: &Path) -> MyResult<Self> {
+        let metadata = fs::metadata(path)?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Ok(FileStamp {
+            size: metadata.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+        })
+    }
+}
+
+/// First line of a cache entry: the file and the options its keys were extracted with.
+///
+/// The second line is the `EfdFileKeys` of the file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EntryHeader {
+    version: u32,
+    signature: String,
+    /// Canonical path of the EFD file.
+    path: PathBuf,
+    stamp: FileStamp,
+    /// Blake3 hash of the file content, only known once the file changed after being cached.
+    hash: Option<String>,
+}
+
+/// A persistent cache of the keys and header extracted from each EFD file.
+///
+/// Each file has its own entry in the cache directory, named after its canonical path,
+/// so the entries are read and written one at a time, as the files are processed.
+/// An entry is reused when:
+/// - the file size and modification time are unchanged, or
+/// - the content hash is unchanged (e.g. the file was copied or touched).
+///
+/// Otherwise the file is parsed again and its entry replaced.
+/// The cache is shared by the Rayon workers, so all methods take `&self`.
+pub struct KeyCache {
+    dir: PathBuf,
+    signature: String,
+    /// Ignore the saved entries (see `KeyCache::empty`).
+    rebuild: bool,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl KeyCache {
+    /// Opens the cache saved in `dir`. The directory is created with the first entry.
+    ///
+    /// `signature` identifies the extraction options: entries saved
+    /// with a different signature (or another format version) are never reused.
+    pub fn open<P: AsRef<Path>>(dir: P, signature: &str) -> Self {
+        KeyCache {
+            dir: dir.as_ref().to_path_buf(),
+            signature: signature.to_string(),
+            rebuild: false,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Same as `open`, but ignores the saved entries: every file is parsed
+    /// and its entry replaced.
+    pub fn empty<P: AsRef<Path>>(dir: P, signature: &str) -> Self {
+        KeyCache {
+            rebuild: true,
+            ..KeyCache::open(dir, signature)
+        }
+    }
+
+    /// Returns the cached result for the file at `path`, or calls `extract`
+    /// and stores its result if the file is new or was modified.
+    ///
+    /// Only files whose size or modification time changed are hashed, so a new
+    /// file is read once; its entry gets a hash the next time it changes.
+    /// The returned result keeps `path` as given (not the canonical path).
+    pub fn get_or_extract<F>(&self, path: &Path, extract: F) -> MyResult<EfdFileKeys>
+    where
+        F: FnOnce() -> MyResult<EfdFileKeys>,
+    {
+        let canonical = fs::canonicalize(path)?;
+        let stamp = FileStamp::read(path)?;
+        let entry_path = self.entry_path(&canonical);
+        let mut hash = None;
+
+        if let Some((header, reader)) = self.read_header(&entry_path) {
+            if header.path == canonical && header.stamp == stamp {
+                if let Some(file_keys) = read_file_keys(reader) {
+                    return Ok(self.hit(file_keys, path));
+                }
+            } else if header.path == canonical {
+                let current = blake3_hash(path)?;
+                if header.hash.as_ref() == Some(&current) {
+                    if let Some(file_keys) = read_file_keys(reader) {
+                        // Same content with a new modification time: refresh the stamp.
+                        self.write_entry(
+                            &entry_path,
+                            &EntryHeader { stamp, ..header },
+                            &file_keys,
+                        )?;
+                        return Ok(self.hit(file_keys, path));
+                    }
+                }
+                hash = Some(current);
+            }
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let file_keys = extract()?;
+
+        let header = EntryHeader {
+            version: CACHE_FORMAT_VERSION,
+            signature: self.signature.clone(),
+            path: canonical,
+            stamp,
+            hash,
+        };
+        self.write_entry(&entry_path, &header, &file_keys)?;
+
+        Ok(file_keys)
+    }
+
+    /// Removes the entries of files that no longer exist, and the entries
+    /// of other format versions.
+    ///
+    /// Only files named like the entries (`<hash>.json`) are considered.
+    pub fn prune(&self) -> MyResult<()> {
+        // Nothing was cached yet.
+        let Ok(dir) = fs::read_dir(&self.dir) else {
+            return Ok(());
+        };
+
+        for item in dir {
+            let entry_path = item?.path();
+            if !is_entry_name(&entry_path) {
+                continue;
+            }
+
+            let current = File::open(&entry_path)
+                .ok()
+                .and_then(|file| read_header_line(BufReader::new(file)))
+                .is_some_and(|(header, _)| {
+                    header.version == CACHE_FORMAT_VERSION && header.path.exists()
+                });
+
+            if !current {
+                fs::remove_file(&entry_path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Number of files whose result was reused from the cache.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of files that had to be parsed.
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn hit(&self, mut file_keys: EfdFileKeys, path: &Path) -> EfdFileKeys {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        file_keys.path = path.to_path_buf();
+        file_keys
+    }
+
+    /// File of the entry of the EFD file with this canonical path.
+    fn entry_path(&self, canonical: &Path) -> PathBuf {
+        let name = blake3::hash(canonical.as_os_str().as_encoded_bytes());
+        self.dir.join(format!("{}.json", name.to_hex()))
+    }
+
+    /// Header of a saved entry with the signature and the format version of this cache,
+    /// and the reader positioned at its keys.
+    fn read_header(&self, entry_path: &Path) -> Option<(EntryHeader, BufReader<File>)> {
+        if self.rebuild {
+            return None;
+        }
+
+        let file = File::open(entry_path).ok()?;
+        read_header_line(BufReader::new(file)).filter(|(header, _)| {
+            header.version == CACHE_FORMAT_VERSION && header.signature == self.signature
+        })
+    }
+
+    /// Writes an entry to a temporary file first and then renames it,
+    /// so an interrupted run never leaves a truncated entry behind.
+    fn write_entry(
+        &self,
+        entry_path: &Path,
+        header: &EntryHeader,
+        file_keys: &EfdFileKeys,
+    ) -> MyResult<()> {
+        let write_error = |e| MyError::FileWriteError(entry_path.to_path_buf(), e);
+
+        fs::create_dir_all(&self.dir).map_err(write_error)?;
+        let temp_file = NamedTempFile::new_in(&self.dir).map_err(write_error)?;
+
+        let mut writer = BufWriter::new(temp_file.as_file());
+        serde_json::to_writer(&mut writer, header)?;
+        writeln!(writer)?;
+        serde_json::to_writer(&mut writer, file_keys)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        drop(writer);
+
+        temp_file
+            .persist(entry_path)
+            .map_err(|e| write_error(e.error))?;
+        Ok(())
+    }
+}
+
+/// Reads the first line of an entry.
+fn read_header_line(mut reader: BufReader<File>) -> Option<(EntryHeader, BufReader<File>)> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let header = serde_json::from_str(&line).ok()?;
+    Some((header, reader))
+}
+
+/// Reads the second line of an entry. A damaged entry is treated as missing.
+fn read_file_keys(reader: BufReader<File>) -> Option<EfdFileKeys> {
+    serde_json::from_reader(reader).ok()
+}
+
+/// Returns `true` for the names of the entry files: a blake3 hash with the `.json` extension.
+fn is_entry_name(path: &Path) -> bool {
+    let hex = |stem: &str| stem.len() == 64 && stem.bytes().all(|b| b.is_ascii_hexdigit());
+    path.extension().is_some_and(|ext| ext == "json")
+        && path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .is_some_and(hex)
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output cache_tests
+#[cfg(test)]
+mod cache_tests {
+    use super::*;
+    use crate::read_efd_file;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn unchanged_files_are_loaded_from_cache() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let efd_path = temp_dir.path().join("PISCOFINS_CACHE.txt");
+        let cache_dir = temp_dir.path().join(DEFAULT_CACHE_DIR);
+
+        fs::write(
+            &efd_path,
+            format!(
+                "|0000|006|0|||01012025|31012025|EMPRESA|12345678000190|SP|\n|C100|{}|\n",
+                "1".repeat(44)
+            ),
+        )?;
+
+        let cache = KeyCache::open(&cache_dir, "sig");
+        let first = cache.get_or_extract(&efd_path, || read_efd_file(&efd_path))?;
+        assert_eq!((cache.hits(), cache.misses()), (0, 1));
+
+        // A new run reads the saved entry: `extract` must not be called.
+        let cache = KeyCache::open(&cache_dir, "sig");
+        let second = cache.get_or_extract(&efd_path, || Err(MyError::SinkClosed))?;
+        assert_eq!((cache.hits(), cache.misses()), (1, 0));
+        assert_eq!(first, second);
+        assert!(second.header.is_some());
+
+        // Other extraction options: the saved entries are ignored.
+        let cache = KeyCache::open(&cache_dir, "other");
+        cache.get_or_extract(&efd_path, || read_efd_file(&efd_path))?;
+        assert_eq!((cache.hits(), cache.misses()), (0, 1));
+
+        // With --rebuild-cache too.
+        let cache = KeyCache::empty(&cache_dir, "other");
+        cache.get_or_extract(&efd_path, || read_efd_file(&efd_path))?;
+        assert_eq!((cache.hits(), cache.misses()), (0, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn modified_files_are_parsed_again() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let efd_path = temp_dir.path().join("PISCOFINS_CACHE.txt");
+        let cache = KeyCache::open(temp_dir.path().join(DEFAULT_CACHE_DIR), "sig");
+
+        fs::write(&efd_path, format!("|C100|{}|\n", "1".repeat(44)))?;
+        cache.get_or_extract(&efd_path, || read_efd_file(&efd_path))?;
+
+        fs::write(&efd_path, format!("|C100|{}|\n|C100|x|\n", "2".repeat(44)))?;
+        let file_keys = cache.get_or_extract(&efd_path, || read_efd_file(&efd_path))?;
+
+        assert_eq!((cache.hits(), cache.misses()), (0, 2));
+        assert_eq!(file_keys.chaves, ["2".repeat(44).parse()?]);
+
+        // The hash of a file changed since it was cached is stored with its new entry...
+        fs::write(&efd_path, format!("|C100|{}|\n|C100|yy|\n", "3".repeat(44)))?;
+        cache.get_or_extract(&efd_path, || read_efd_file(&efd_path))?;
+
+        // ...so touching it again does not require parsing it.
+        File::options()
+            .write(true)
+            .open(&efd_path)?
+            .set_modified(UNIX_EPOCH + Duration::from_secs(86_400))?;
+        let touched = FileStamp::read(&efd_path)?;
+        let file_keys = cache.get_or_extract(&efd_path, || Err(MyError::SinkClosed))?;
+        assert_eq!((cache.hits(), cache.misses()), (1, 3));
+        assert_eq!(file_keys.chaves, ["3".repeat(44).parse()?]);
+
+        // The refreshed stamp is reused without hashing.
+        let cache = KeyCache::open(temp_dir.path().join(DEFAULT_CACHE_DIR), "sig");
+        let entry_path = cache.entry_path(&fs::canonicalize(&efd_path)?);
+        let (header, _) = cache.read_header(&entry_path).ok_or(MyError::SinkClosed)?;
+        assert_eq!(header.stamp, touched);
+        Ok(())
+    }
+
+    #[test]
+    fn entries_of_removed_files_are_pruned() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let cache_dir = temp_dir.path().join(DEFAULT_CACHE_DIR);
+        let cache = KeyCache::open(&cache_dir, "sig");
+
+        let kept = temp_dir.path().join("PISCOFINS_KEPT.txt");
+        let removed = temp_dir.path().join("PISCOFINS_REMOVED.txt");
+        for path in [&kept, &removed] {
+            fs::write(path, format!("|C100|{}|\n", "1".repeat(44)))?;
+            cache.get_or_extract(path, || read_efd_file(path))?;
+        }
+        let removed_entry = cache.entry_path(&fs::canonicalize(&removed)?);
+        fs::remove_file(&removed)?;
+
+        // Other files in the directory are never removed.
+        let other = cache_dir.join("notas.json");
+        fs::write(&other, "{}")?;
+
+        cache.prune()?;
+        assert!(!removed_entry.exists());
+        assert!(other.exists());
+        assert_eq!(fs::read_dir(&cache_dir)?.count(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,480 @@
+use crate::{
+    codigos::{nome_modelo, nome_tp_emis, sigla_uf},
+    error::{MyError, MyResult},
+};
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+
+/// Number of characters in an electronic document access key.
+pub const CHAVE44_LEN: usize = 44;
+
+/// Number of bytes used by the packed representation of a `Chave44`.
+pub(crate) const PACKED_LEN: usize = 25;
+
+/// Positions (0-based, half-open) of the issuer CNPJ base that may be
+/// alphanumeric since the 2026 CNPJ layout change.
+///
+/// The last two CNPJ characters (check digits) remain numeric.
+const ALFANUMERIC_RANGE: std::ops::Range<usize> = 6..18;
+
+/// A 44-character electronic document key (NF-e, CT-e, NFC-e, ...) stored
+/// as a packed fixed-size value.
+///
+/// Numeric positions use 4 bits and the 12 positions of the CNPJ base
+/// (which may be alphanumeric) use 6 bits, for a total of 200 bits.
+/// Bits are packed big-endian in key order and every character code preserves
+/// the ASCII order (`'0'..='9' < 'A'..='Z'`), so the derived `Ord` sorts keys
+/// exactly like their textual form.
+///
+/// A `Chave44` is `Copy` and takes 25 bytes, against more than 70 bytes
+/// (plus allocator overhead) for a `String`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Chave44 {
+    packed: [u8; PACKED_LEN],
+}
+
+/// The kind of a `Chave44`, based on the characters of its CNPJ field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TipoChave {
+    /// All 44 characters are digits.
+    Numerica,
+    /// The CNPJ base contains letters (alphanumeric CNPJ, 2026 onwards).
+    Alfanumerica,
+}
+
+/// Classification of a 44-character sequence found in an EFD file.
+///
+/// Besides access keys, bank payment slip (boleto) and collection (arrecadação)
+/// barcodes also have 44 digits and appear in free-text fields.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, ValueEnum, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClasseChave {
+    /// Electronic document access key: valid check digit, UF code and model.
+    ChaveAcesso,
+    /// Bank payment slip barcode: currency code 9 (Real) in position 4
+    /// and valid mod 11 general check digit in position 5.
+    Boleto,
+    /// Collection barcode (utilities, taxes): starts with 8.
+    Arrecadacao,
+    /// Any other 44-character sequence.
+    Desconhecida,
+}
+
+impl ClasseChave {
+    /// Name of the class, as used on the command line and in the outputs.
+    pub fn nome(self) -> &'static str {
+        match self {
+            ClasseChave::ChaveAcesso => "chave-acesso",
+            ClasseChave::Boleto => "boleto",
+            ClasseChave::Arrecadacao => "arrecadacao",
+            ClasseChave::Desconhecida => "desconhecida",
+        }
+    }
+}
+
+impl fmt::Display for ClasseChave {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.nome())
+    }
+}
+
+/// Weighted sum used by the mod 11 check digits: weights 2 to 9,
+/// repeated from right to left.
+fn modulo11_sum(values: impl DoubleEndedIterator<Item = u32>) -> u32 {
+    values
+        .rev()
+        .zip((2..=9).cycle())
+        .map(|(value, weight)| value * weight)
+        .sum()
+}
+
+/// Width in bits of the character stored at `position`.
+fn bit_width(position: usize) -> usize {
+    if ALFANUMERIC_RANGE.contains(&position) {
+        6
+    } else {
+        4
+    }
+}
+
+/// Converts an ASCII character to its code: '0'..='9' => 0..=9, 'A'..='Z' => 10..=35.
+fn char_to_code(byte: u8, position: usize) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'A'..=b'Z' | b'a'..=b'z' if ALFANUMERIC_RANGE.contains(&position) => {
+            Some(byte.to_ascii_uppercase() - b'A' + 10)
+        }
+        _ => None,
+    }
+}
+
+/// Converts a code back to its (uppercase) ASCII character.
+fn code_to_char(code: u8) -> u8 {
+    if code < 10 {
+        b'0' + code
+    } else {
+        b'A' + code - 10
+    }
+}
+
+impl Chave44 {
+    /// Builds a `Chave44` from its textual form.
+    ///
+    /// Returns `None` if the text does not have exactly 44 characters,
+    /// or if a letter appears outside the CNPJ base (positions 7 to 18).
+    /// Lowercase letters are normalized to uppercase.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != CHAVE44_LEN {
+            return None;
+        }
+
+        let mut packed = [0u8; PACKED_LEN];
+        let mut bit_offset = 0;
+
+        for (position, &byte) in bytes.iter().enumerate() {
+            let code = char_to_code(byte, position)?;
+            let width = bit_width(position);
+
+            for bit in (0..width).rev() {
+                if (code >> bit) & 1 == 1 {
+                    packed[bit_offset / 8] |= 0x80 >> (bit_offset % 8);
+                }
+                bit_offset += 1;
+            }
+        }
+
+        Some(Chave44 { packed })
+    }
+
+    /// Returns the 44 ASCII characters of the key.
+    pub fn to_ascii(&self) -> [u8; CHAVE44_LEN] {
+        let mut ascii = [0u8; CHAVE44_LEN];
+        let mut bit_offset = 0;
+
+        for (position, byte) in ascii.iter_mut().enumerate() {
+            let mut code = 0u8;
+
+            for _ in 0..bit_width(position) {
+                let bit = (self.packed[bit_offset / 8] >> (7 - bit_offset % 8)) & 1;
+                code = (code << 1) | bit;
+                bit_offset += 1;
+            }
+
+            *byte = code_to_char(code);
+        }
+
+        ascii
+    }
+
+    /// Returns the packed representation, used to store keys in binary files.
+    pub(crate) fn to_packed(self) -> [u8; PACKED_LEN] {
+        self.packed
+    }
+
+    /// Rebuilds a key from the bytes returned by `to_packed`.
+    pub(crate) fn from_packed(packed: [u8; PACKED_LEN]) -> Self {
+        Chave44 { packed }
+    }
+
+    /// IBGE code of the issuer state (cUF, positions 1-2).
+    pub fn codigo_uf(&self) -> u8 {
+        self.numero_em(0..2) as u8
+    }
+
+    /// Document model (mod, positions 21-22), e.g. 55 for NF-e.
+    pub fn modelo(&self) -> u8 {
+        self.numero_em(20..22) as u8
+    }
+
+    /// Year and month of issue (AAMM, positions 3-6), e.g. "2506".
+    pub fn aamm(&self) -> String {
+        self.texto_em(2..6)
+    }
+
+    /// Issuer CNPJ (positions 7-20), which may be alphanumeric.
+    ///
+    /// Documents issued by individuals have "000" followed by the CPF.
+    pub fn cnpj(&self) -> String {
+        self.texto_em(6..20)
+    }
+
+    /// Series (serie, positions 23-25).
+    pub fn serie(&self) -> u16 {
+        self.numero_em(22..25) as u16
+    }
+
+    /// Document number (nNF, positions 26-34).
+    pub fn numero(&self) -> u32 {
+        self.numero_em(25..34) as u32
+    }
+
+    /// Type of issue (tpEmis, position 35): 1 normal; the other codes depend
+    /// on the model (see `nome_tp_emis`).
+    pub fn tp_emis(&self) -> u8 {
+        self.numero_em(34..35) as u8
+    }
+
+    /// Returns `true` if the type of issue is defined for the model of the key.
+    pub fn tp_emis_conhecido(&self) -> bool {
+        nome_tp_emis(self.modelo(), self.tp_emis()).is_some()
+    }
+
+    /// Returns `true` if the document was issued in contingency: a type of issue
+    /// defined for its model, other than normal (1) and the special regime
+    /// of Nota Fiscal Fácil (3).
+    ///
+    /// Such documents must be followed up to confirm their later authorization.
+    /// Unknown codes are not contingency: see `tp_emis_conhecido`.
+    pub fn contingencia(&self) -> bool {
+        self.tp_emis_conhecido() && !matches!(self.tp_emis(), 1 | 3)
+    }
+
+    /// Check digit informed in the key (cDV, position 44).
+    pub fn dv(&self) -> u8 {
+        self.numero_em(43..44) as u8
+    }
+
+    /// Check digit computed from the first 43 characters (mod 11).
+    ///
+    /// For alphanumeric CNPJs, the value of each character is its ASCII code
+    /// minus 48, so digits keep their values and 'A' is worth 17.
+    pub fn dv_calculado(&self) -> u8 {
+        let ascii = self.to_ascii();
+        let sum = modulo11_sum(ascii[..43].iter().map(|&byte| u32::from(byte - b'0')));
+
+        match sum % 11 {
+            0 | 1 => 0,
+            resto => (11 - resto) as u8,
+        }
+    }
+
+    /// Returns `true` if the check digit matches the first 43 characters.
+    pub fn dv_valido(&self) -> bool {
+        self.dv() == self.dv_calculado()
+    }
+
+    /// Classifies the sequence as an access key, a boleto or collection barcode, or unknown.
+    pub fn classe(&self) -> ClasseChave {
+        let ascii = self.to_ascii();
+
+        if self.dv_valido()
+            && sigla_uf(self.codigo_uf()).is_some()
+            && nome_modelo(self.modelo()).is_some()
+        {
+            ClasseChave::ChaveAcesso
+        } else if self.tipo() == TipoChave::Alfanumerica {
+            // Barcodes only have digits.
+            ClasseChave::Desconhecida
+        } else if ascii[0] == b'8' {
+            ClasseChave::Arrecadacao
+        } else if ascii[3] == b'9' && boleto_dv_valido(&ascii) {
+            ClasseChave::Boleto
+        } else {
+            ClasseChave::Desconhecida
+        }
+    }
+
+    /// Characters in `range` (0-based, half-open).
+    fn texto_em(&self, range: std::ops::Range<usize>) -> String {
+        String::from_utf8_lossy(&self.to_ascii()[range]).into_owned()
+    }
+
+    /// Numeric value of the digits in `range` (0-based, half-open).
+    fn numero_em(&self, range: std::ops::Range<usize>) -> u64 {
+        self.to_ascii()[range].iter().fold(0, |number, &byte| {
+            number * 10 + u64::from(byte.wrapping_sub(b'0') % 36)
+        })
+    }
+
+    /// Returns whether the key is purely numeric or has an alphanumeric CNPJ.
+    pub fn tipo(&self) -> TipoChave {
+        if self.to_ascii()[ALFANUMERIC_RANGE]
+            .iter()
+            .all(u8::is_ascii_digit)
+        {
+            TipoChave::Numerica
+        } else {
+            TipoChave::Alfanumerica
+        }
+    }
+}
+
+impl FromStr for Chave44 {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> MyResult<Self> {
+        Chave44::from_bytes(s.as_bytes()).ok_or_else(|| MyError::InvalidKey(s.to_string()))
+    }
+}
+
+impl fmt::Display for Chave44 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ascii = self.to_ascii();
+        // The decoded characters are always ASCII digits or uppercase letters.
+        f.write_str(std::str::from_utf8(&ascii).map_err(|_| fmt::Error)?)
+    }
+}
+
+impl fmt::Debug for Chave44 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Chave44({self})")
+    }
+}
+
+/// Checks the general check digit (position 5) of a bank payment slip barcode.
+///
+/// Mod 11 over the other 43 digits; results 0, 10 and 11 become 1.
+fn boleto_dv_valido(ascii: &[u8; CHAVE44_LEN]) -> bool {
+    let values = ascii
+        .iter()
+        .enumerate()
+        .filter(|(position, _)| *position != 4)
+        .map(|(_, &byte)| u32::from(byte - b'0'));
+
+    let dv = match 11 - modulo11_sum(values) % 11 {
+        0 | 10 | 11 => 1,
+        dv => dv,
+    };
+
+    u32::from(ascii[4] - b'0') == dv
+}
+
+/// Keys are serialized as their 44-character text.
+impl Serialize for Chave44 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Chave44 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Sorts the keys and removes duplicates in place.
+pub fn sort_and_dedup(chaves: &mut Vec<Chave44>) {
+    chaves.sort_unstable();
+    chaves.dedup();
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output chave_tests
+#[cfg(test)]
+mod chave_tests {
+    use super::*;
+
+    #[test]
+    fn packed_key_round_trip() -> MyResult<()> {
+        let texts = [
+            "35250612345678000190550010000001231123456780",
+            "00000000000000000000000000000000000000000000",
+            "99999999999999999999999999999999999999999999",
+            "352506ABCDEFGHIJ0190550010000001231123456780",
+            "352506ZZZZZZZZZZZZ90550010000001231123456789",
+        ];
+
+        for text in texts {
+            let chave: Chave44 = text.parse()?;
+            assert_eq!(chave.to_string(), text);
+        }
+
+        assert_eq!(std::mem::size_of::<Chave44>(), PACKED_LEN);
+        Ok(())
+    }
+
+    #[test]
+    fn alphanumeric_keys() -> MyResult<()> {
+        let numerica: Chave44 = "35250612345678000190550010000001231123456780".parse()?;
+        let alfanumerica: Chave44 = "352506ab3c5d7e000190550010000001231123456780".parse()?;
+
+        assert_eq!(numerica.tipo(), TipoChave::Numerica);
+        assert_eq!(alfanumerica.tipo(), TipoChave::Alfanumerica);
+        assert_eq!(
+            alfanumerica.to_string(),
+            "352506AB3C5D7E000190550010000001231123456780"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_digit_and_classification() -> MyResult<()> {
+        // NF-e from SP (35), model 55, with a valid check digit.
+        let nfe: Chave44 = "35250612345678000190550010000001231123456782".parse()?;
+        assert_eq!(nfe.codigo_uf(), 35);
+        assert_eq!(nfe.modelo(), 55);
+        assert_eq!(nfe.aamm(), "2506");
+        assert_eq!(nfe.cnpj(), "12345678000190");
+        assert_eq!((nfe.serie(), nfe.numero(), nfe.tp_emis()), (1, 123, 1));
+        assert!(nfe.dv_valido());
+        assert_eq!(nfe.classe(), ClasseChave::ChaveAcesso);
+
+        // Same key with a wrong check digit.
+        let wrong_dv: Chave44 = "35250612345678000190550010000001231123456780".parse()?;
+        assert!(!wrong_dv.dv_valido());
+        assert_eq!(wrong_dv.classe(), ClasseChave::Desconhecida);
+
+        // Alphanumeric CNPJ: letters are worth their ASCII code minus 48.
+        let alfanumerica: Chave44 = "352506AB3C5D7E000190550010000001231123456784".parse()?;
+        assert_eq!(alfanumerica.dv_calculado(), 4);
+        assert_eq!(alfanumerica.classe(), ClasseChave::ChaveAcesso);
+
+        // Bank payment slip barcode (Banco do Brasil, R$ 1.000,00).
+        let boleto: Chave44 = "00193373700000001000500940144816060680935031".parse()?;
+        assert_eq!(boleto.classe(), ClasseChave::Boleto);
+
+        // Collection barcode.
+        let arrecadacao: Chave44 = "83640000001110900480035602202106101049580190".parse()?;
+        assert_eq!(arrecadacao.classe(), ClasseChave::Arrecadacao);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        // Wrong length
+        assert!("3525061234567800019055001000000123112345678"
+            .parse::<Chave44>()
+            .is_err());
+        // Letter outside the CNPJ base
+        assert!("4444A444444444444444444444444444444444444444"
+            .parse::<Chave44>()
+            .is_err());
+        // Letter in the CNPJ check digits
+        assert!("352506123456780001A0550010000001231123456780"
+            .parse::<Chave44>()
+            .is_err());
+    }
+
+    #[test]
+    fn packed_order_matches_text_order() -> MyResult<()> {
+        let mut texts = vec![
+            "35250612345678000190550010000001231123456780",
+            "352506ABCDEFGHIJ0190550010000001231123456780",
+            "35250612345678000190550010000001231123456781",
+            "352506123456789Z0190550010000001231123456780",
+            "11111111111111111111111111111111111111111111",
+            "35250612345678000190550010000001231123456780",
+        ];
+
+        let mut chaves: Vec<Chave44> = texts
+            .iter()
+            .map(|text| text.parse())
+            .collect::<MyResult<_>>()?;
+
+        texts.sort_unstable();
+        texts.dedup();
+        sort_and_dedup(&mut chaves);
+
+        let sorted: Vec<String> = chaves.iter().map(Chave44::to_string).collect();
+        assert_eq!(sorted, texts);
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+/// IBGE codes of the Brazilian states, as used in the cUF field of the keys.
+pub const UFS: [(u8, &str); 27] = [
+    (11, "RO"),
+    (12, "AC"),
+    (13, "AM"),
+    (14, "RR"),
+    (15, "PA"),
+    (16, "AP"),
+    (17, "TO"),
+    (21, "MA"),
+    (22, "PI"),
+    (23, "CE"),
+    (24, "RN"),
+    (25, "PB"),
+    (26, "PE"),
+    (27, "AL"),
+    (28, "SE"),
+    (29, "BA"),
+    (31, "MG"),
+    (32, "ES"),
+    (33, "RJ"),
+    (35, "SP"),
+    (41, "PR"),
+    (42, "SC"),
+    (43, "RS"),
+    (50, "MS"),
+    (51, "MT"),
+    (52, "GO"),
+    (53, "DF"),
+];
+
+/// Document models that use a 44-character access key.
+pub const MODELOS: [(u8, &str); 9] = [
+    (55, "NF-e"),
+    (57, "CT-e"),
+    (58, "MDF-e"),
+    (59, "CF-e SAT"),
+    (62, "NFCom"),
+    (63, "BP-e"),
+    (65, "NFC-e"),
+    (66, "NF3e"),
+    (67, "CT-e OS"),
+];
+
+/// Returns the state abbreviation (e.g. "SP") of an IBGE state code (e.g. 35).
+pub fn sigla_uf(codigo: u8) -> Option<&'static str> {
+    UFS.iter()
+        .find(|(code, _)| *code == codigo)
+        .map(|(_, sigla)| *sigla)
+}
+
+/// Returns the IBGE state code (e.g. 35) of a state abbreviation (e.g. "sp").
+pub fn codigo_uf(sigla: &str) -> Option<u8> {
+    UFS.iter()
+        .find(|(_, uf)| uf.eq_ignore_ascii_case(sigla.trim()))
+        .map(|(code, _)| *code)
+}
+
+/// Returns the name (e.g. "NF-e") of a document model (e.g. 55).
+pub fn nome_modelo(modelo: u8) -> Option<&'static str> {
+    MODELOS
+        .iter()
+        .find(|(code, _)| *code == modelo)
+        .map(|(_, nome)| *nome)
+}
+
+/// Returns the name of a type of issue (tpEmis, position 35 of the key) of a document model.
+///
+/// NF-e and NFC-e: 2 FS-IA, 4 EPEC, 5 FS-DA, 6 SVC-AN, 7 SVC-RS, 9 offline NFC-e;
+/// CT-e: 4 EPEC, 5 FS-DA, 7 SVC-RS, 8 SVC-SP; other models: 2 contingency (offline).
+/// Type 3 is the special regime of Nota Fiscal Fácil (formerly SCAN).
+pub fn nome_tp_emis(modelo: u8, tp_emis: u8) -> Option<&'static str> {
+    let nome = match (modelo, tp_emis) {
+        (_, 1) => "normal",
+        (_, 3) => "regime especial NFF",
+        (55 | 65, 2) => "FS-IA",
+        (55 | 65 | 57 | 67, 4) => "EPEC",
+        (55 | 65 | 57 | 67, 5) => "FS-DA",
+        (55 | 65, 6) => "SVC-AN",
+        (55 | 65 | 57 | 67, 7) => "SVC-RS",
+        (57 | 67, 8) => "SVC-SP",
+        (65, 9) => "offline NFC-e",
+        (58 | 59 | 62 | 63 | 66, 2) => "contingencia",
+        _ => return None,
+    };
+    Some(nome)
+}
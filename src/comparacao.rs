@@ -0,0 +1,280 @@
+use crate::{
+    error::{MyError, MyResult},
+    extract_efd_file, is_efd_file, Chave44, ClasseChave, EfdFileKeys, ExtractionConfig,
+};
+use clap::ValueEnum;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+use walkdir::{DirEntry, WalkDir};
+
+/// Groups compared separately by `comparar`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Agrupamento {
+    /// Declaring CNPJ (record 0000).
+    Cnpj,
+    /// Month of the bookkeeping (DT_INI of record 0000), as `aaaa-mm`.
+    Periodo,
+}
+
+/// Where a key was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Presenca {
+    SomenteA,
+    SomenteB,
+    Ambos,
+}
+
+/// A key of the comparison, with the first file of each side where it was found.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiferencaChave {
+    pub chave: Chave44,
+    pub classe: ClasseChave,
+    pub presenca: Presenca,
+    /// Group of the key (empty if not grouped by CNPJ or period).
+    pub cnpj: String,
+    pub periodo: String,
+    pub arquivo_a: Option<PathBuf>,
+    pub arquivo_b: Option<PathBuf>,
+}
+
+/// Reads the keys of one side of a comparison:
+/// - a directory, searched recursively for EFD files,
+/// - an EFD file (named as in the layout, e.g. `PISCOFINS*.txt`),
+/// - an output saved by a previous run, in any format (see `read_saved_output`).
+pub fn read_keys_input(path: &Path, config: &ExtractionConfig) -> MyResult<Vec<EfdFileKeys>> {
+    if !path.try_exists()? {
+        return Err(MyError::PathNotFound(path.to_path_buf()));
+    }
+
+    let entries: Vec<DirEntry> = WalkDir::new(path)
+        .into_iter()
+        .collect::<Result<Vec<DirEntry>, walkdir::Error>>()?
+        .into_iter()
+        .filter(|entry| is_efd_file(entry, &config.layout))
+        .collect();
+
+    if path.is_file() && entries.is_empty() {
+        return read_saved_output(path);
+    }
+
+    entries
+        .par_iter()
+        .map(|entry| extract_efd_file(entry, config))
+        .collect()
+}
+
+/// Reads the keys of an output file written by a previous run.
+///
+/// `.csv` and `.ndjson` files are read with their `chave` and `arquivo` fields,
+/// and the keys are grouped by the EFD file where they were found.
+/// Other files must have one key per line. Saved outputs have no 0000 record,
+/// so their keys have no CNPJ or period.
+pub fn read_saved_output(path: &Path) -> MyResult<Vec<EfdFileKeys>> {
+    let file = File::open(path).map_err(|e| MyError::FileReadError(path.to_path_buf(), e))?;
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+
+    let mut arquivos: BTreeMap<PathBuf, Vec<Chave44>> = BTreeMap::new();
+    let mut push = |chave: &str, arquivo: Option<&str>| -> MyResult<()> {
+        let arquivo = arquivo.map_or_else(|| path.to_path_buf(), PathBuf::from);
+        arquivos.entry(arquivo).or_default().push(chave.parse()?);
+        Ok(())
+    };
+
+    match extension.as_deref() {
+        Some("csv") => {
+            let mut reader = csv::Reader::from_reader(file);
+            let headers = reader.headers()?.clone();
+            let coluna = |nome: &str| headers.iter().position(|h| h == nome);
+            let (Some(chave), arquivo) = (coluna("chave"), coluna("arquivo")) else {
+                return Err(MyError::InvalidOptions(format!(
+                    "'{}' has no 'chave' column",
+                    path.display()
+                )));
+            };
+
+            for record in reader.records() {
+                let record = record?;
+                push(&record[chave], arquivo.and_then(|idx| record.get(idx)))?;
+            }
+        }
+        Some("ndjson") => {
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let value: serde_json::Value = serde_json::from_str(&line)?;
+                let chave = value["chave"].as_str().unwrap_or_default();
+                push(chave, value["arquivo"].as_str())?;
+            }
+        }
+        _ => {
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    push(line.trim(), None)?;
+                }
+            }
+        }
+    }
+
+    Ok(arquivos
+        .into_iter()
+        .map(|(path, chaves)| {
+            let mut file_keys = EfdFileKeys::new(&path);
+            file_keys.chaves = chaves;
+            file_keys.finish()
+        })
+        .collect())
+}
+
+/// Compares the keys of two sets of files.
+///
+/// With `agrupamentos`, a key is compared only with the keys of the same CNPJ
+/// and/or period: the same key in two periods is then listed once per period.
+/// Results are sorted by group, then by key.
+pub fn comparar(
+    a: &[EfdFileKeys],
+    b: &[EfdFileKeys],
+    agrupamentos: &[Agrupamento],
+) -> Vec<DiferencaChave> {
+    type Grupo = (String, String, Chave44);
+    let mut chaves: BTreeMap<Grupo, (Option<PathBuf>, Option<PathBuf>)> = BTreeMap::new();
+
+    let grupo = |file_keys: &EfdFileKeys| {
+        let header = file_keys.header.as_ref();
+        let cnpj = agrupamentos
+            .contains(&Agrupamento::Cnpj)
+            .then(|| header.map(|h| h.cnpj.clone()))
+            .flatten()
+            .unwrap_or_default();
+        let periodo = agrupamentos
+            .contains(&Agrupamento::Periodo)
+            .then(|| header.and_then(|h| h.periodo()))
+            .flatten()
+            .unwrap_or_default();
+        (cnpj, periodo)
+    };
+
+    for (lado, arquivos) in [a, b].into_iter().enumerate() {
+        for file_keys in arquivos {
+            let (cnpj, periodo) = grupo(file_keys);
+            for &chave in &file_keys.chaves {
+                let entry = chaves
+                    .entry((cnpj.clone(), periodo.clone(), chave))
+                    .or_default();
+                let arquivo = if lado == 0 {
+                    &mut entry.0
+                } else {
+                    &mut entry.1
+                };
+                arquivo.get_or_insert_with(|| file_keys.path.clone());
+            }
+        }
+    }
+
+    chaves
+        .into_iter()
+        .map(|((cnpj, periodo, chave), (arquivo_a, arquivo_b))| {
+            let presenca = match (&arquivo_a, &arquivo_b) {
+                (Some(_), Some(_)) => Presenca::Ambos,
+                (Some(_), None) => Presenca::SomenteA,
+                _ => Presenca::SomenteB,
+            };
+            DiferencaChave {
+                chave,
+                classe: chave.classe(),
+                presenca,
+                cnpj,
+                periodo,
+                arquivo_a,
+                arquivo_b,
+            }
+        })
+        .collect()
+}
+
+/// Writes the comparison as CSV, with a header line.
+pub fn write_diff<P>(diferencas: &[DiferencaChave], output_file: P) -> MyResult<()>
+where
+    P: AsRef<Path>,
+{
+    let path = output_file.as_ref();
+    let file = File::create(path).map_err(|e| MyError::FileWriteError(path.to_path_buf(), e))?;
+    let mut writer = csv::Writer::from_writer(BufWriter::new(file));
+
+    for diferenca in diferencas {
+        writer.serialize(diferenca)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output comparacao_tests
+#[cfg(test)]
+mod comparacao_tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn original_and_rectifying_files_are_compared() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let chave1 = "35250612345678000190550010000001231123456782";
+        let chave2 = "35250612345678000190550010000001241123456789";
+        let chave3 = "352506AB3C5D7E000190550010000001231123456784";
+
+        let original = temp_dir.path().join("PISCOFINS_ORIGINAL.txt");
+        fs::write(
+            &original,
+            format!(
+                "|0000|006|0|||01062025|30062025|EMPRESA|99999999000191|SP|\n\
+                 |C100|0|1|F1|55|00|1|123|{chave1}|15062025|\n\
+                 |C100|0|1|F1|55|00|1|124|{chave2}|15062025|\n"
+            ),
+        )?;
+
+        // A previous run saved as CSV: the keys of the rectifying file.
+        let saved = temp_dir.path().join("retificadora.csv");
+        fs::write(
+            &saved,
+            format!("chave,classe,arquivo\n{chave2},chave-acesso,RET.txt\n{chave3},chave-acesso,RET.txt\n"),
+        )?;
+
+        let config = ExtractionConfig::default();
+        let a = read_keys_input(&original, &config)?;
+        let b = read_keys_input(&saved, &config)?;
+
+        let resumo: Vec<(String, Presenca)> = comparar(&a, &b, &[])
+            .into_iter()
+            .map(|diferenca| (diferenca.chave.to_string(), diferenca.presenca))
+            .collect();
+
+        assert_eq!(
+            resumo,
+            [
+                (chave1.to_string(), Presenca::SomenteA),
+                (chave2.to_string(), Presenca::Ambos),
+                (chave3.to_string(), Presenca::SomenteB),
+            ]
+        );
+        assert_eq!(b[0].path, PathBuf::from("RET.txt"));
+        Ok(())
+    }
+}
@@ -0,0 +1,483 @@
+use crate::{
+    error::{MyError, MyResult},
+    Chave44, ClasseChave, EfdFileKeys, KeySink, CHAVE44_LEN,
+};
+use calamine::Reader;
+use encoding_rs::{Encoding, WINDOWS_1252};
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufWriter, Read},
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+/// Elements with the key of an authorized document (`protNFe/infProt/chNFe`, ...).
+const ELEMENTOS_CHAVE: [&str; 3] = ["chNFe", "chCTe", "chMDFe"];
+
+/// Elements whose `Id` attribute is the key with a prefix (`infNFe Id="NFe3525..."`).
+const ELEMENTOS_ID: [&str; 3] = ["infNFe", "infCte", "infMDFe"];
+
+/// A key found outside the EFD files: in an XML document or in an official list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChaveExterna {
+    pub chave: Chave44,
+    /// File where the key was found (`arquivo.zip/nota.xml` for XMLs inside ZIPs).
+    pub origem: String,
+}
+
+/// Why a key is listed by `ConciliacaoSink::conciliar`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Pendencia {
+    /// Found in the external source, but not declared in the EFD files.
+    AusenteNaEfd,
+    /// Declared in the EFD files, but not found in the external source.
+    AusenteNaFonte,
+}
+
+/// A key found in only one side of the reconciliation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Conciliacao {
+    pub chave: Chave44,
+    /// Document model (55 NF-e, 57 CT-e, 65 NFC-e...).
+    pub modelo: u8,
+    pub pendencia: Pendencia,
+    /// First EFD file where the key was found.
+    pub arquivo_efd: Option<PathBuf>,
+    /// First external file where the key was found.
+    pub origem: Option<String>,
+}
+
+/// Returns the key of an NF-e, NFC-e, CT-e or MDF-e XML document.
+///
+/// The key of the authorization protocol (`protNFe/infProt/chNFe`) is preferred;
+/// otherwise the `Id` attribute of `infNFe` (or `infCte`, `infMDFe`) is used.
+/// Event XMLs (`procEventoNFe`, ...) cite a key but are not documents: see `evento_do_xml`.
+pub fn chave_do_xml(xml: &str) -> Option<Chave44> {
+    let documento = roxmltree::Document::parse(xml).ok()?;
+    if documento
+        .root_element()
+        .tag_name()
+        .name()
+        .contains("Evento")
+    {
+        return None;
+    }
+    let elementos = || documento.descendants().filter(|node| node.is_element());
+
+    let protocolo = elementos()
+        .filter(|node| ELEMENTOS_CHAVE.contains(&node.tag_name().name()))
+        .find_map(|node| Chave44::from_bytes(node.text()?.trim().as_bytes()));
+
+    protocolo.or_else(|| {
+        elementos()
+            .filter(|node| ELEMENTOS_ID.contains(&node.tag_name().name()))
+            .find_map(|node| {
+                let id = node.attribute("Id")?;
+                let chave = id.get(id.len().checked_sub(CHAVE44_LEN)?..)?;
+                Chave44::from_bytes(chave.as_bytes())
+            })
+    })
+}
+
+/// Decodes an XML file: UTF-8, or WINDOWS-1252 (ISO-8859-1) for older files.
+fn decode_xml(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(texto) => texto.to_string(),
+        Err(_) => WINDOWS_1252.decode(bytes).0.into_owned(),
+    }
+}
+
+/// Parses each XML document of one `.xml` or `.zip` file with `parse`,
+/// which receives the text and the origin of the document.
+fn read_xml_file<T, F>(path: &Path, parse: &F) -> MyResult<Vec<T>>
+where
+    F: Fn(&str, String) -> Option<T>,
+{
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+
+    let mut resultados = Vec::new();
+
+    match extension.as_deref() {
+        Some("xml") => {
+            let bytes =
+                fs::read(path).map_err(|e| MyError::FileReadError(path.to_path_buf(), e))?;
+            resultados.extend(parse(&decode_xml(&bytes), path.display().to_string()));
+        }
+        Some("zip") => {
+            let file =
+                File::open(path).map_err(|e| MyError::FileReadError(path.to_path_buf(), e))?;
+            let mut archive = zip::ZipArchive::new(file)?;
+
+            for idx in 0..archive.len() {
+                let mut entry = archive.by_index(idx)?;
+                if !entry.is_file() || !entry.name().to_ascii_lowercase().ends_with(".xml") {
+                    continue;
+                }
+
+                let mut bytes = Vec::new();
+                entry.read_to_end(&mut bytes)?;
+                let origem = path.join(entry.name()).display().to_string();
+                resultados.extend(parse(&decode_xml(&bytes), origem));
+            }
+        }
+        _ => {}
+    }
+
+    Ok(resultados)
+}
+
+/// Searches a directory recursively for XML files (also inside ZIP files)
+/// and parses each of them with `parse` (see `read_xml_file`), in parallel.
+pub(crate) fn read_xml_dir_with<T, F>(dir: &Path, parse: F) -> MyResult<Vec<T>>
+where
+    T: Send,
+    F: Fn(&str, String) -> Option<T> + Sync,
+{
+    if !dir.try_exists()? {
+        return Err(MyError::PathNotFound(dir.to_path_buf()));
+    }
+
+    let paths: Vec<PathBuf> = WalkDir::new(dir)
+        .into_iter()
+        .collect::<Result<Vec<_>, walkdir::Error>>()?
+        .into_iter()
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .collect();
+
+    let resultados = paths
+        .par_iter()
+        .map(|path| read_xml_file(path, &parse))
+        .collect::<MyResult<Vec<_>>>()?;
+
+    Ok(resultados.into_iter().flatten().collect())
+}
+
+/// Searches a directory recursively for XML documents (also inside ZIP files)
+/// and returns their keys. XMLs without a key (events, other schemas) are ignored.
+pub fn read_xml_dir(dir: &Path) -> MyResult<Vec<ChaveExterna>> {
+    read_xml_dir_with(dir, |xml, origem| {
+        chave_do_xml(xml).map(|chave| ChaveExterna { chave, origem })
+    })
+}
+
+/// How to read an official list of documents exported as CSV.
+#[derive(Debug, Clone)]
+pub struct OpcoesLista {
+    /// Column with the keys: its header, or its position (1-based).
+    /// By default, the first column whose header contains "chave".
+    pub coluna: Option<String>,
+    /// Field delimiter of CSV files.
+    pub delimitador: u8,
+    /// Encoding of CSV files.
+    pub codificacao: &'static Encoding,
+}
+
+impl Default for OpcoesLista {
+    fn default() -> Self {
+        OpcoesLista {
+            coluna: None,
+            delimitador: b';',
+            codificacao: WINDOWS_1252,
+        }
+    }
+}
+
+impl OpcoesLista {
+    /// Index of the key column, given the header line.
+    fn indice_coluna(&self, cabecalho: &[String], path: &Path) -> MyResult<usize> {
+        let indice = match &self.coluna {
+            Some(coluna) => match coluna.parse::<usize>() {
+                Ok(posicao) => posicao.checked_sub(1),
+                Err(_) => cabecalho
+                    .iter()
+                    .position(|nome| nome.trim().eq_ignore_ascii_case(coluna.trim())),
+            },
+            None => cabecalho
+                .iter()
+                .position(|nome| nome.to_lowercase().contains("chave")),
+        };
+
+        indice.ok_or_else(|| {
+            MyError::InvalidOptions(format!(
+                "key column '{}' not found in '{}' (columns: {})",
+                self.coluna.as_deref().unwrap_or("chave"),
+                path.display(),
+                cabecalho.join(", ")
+            ))
+        })
+    }
+}
+
+/// Reads the keys of a list of issued or received documents downloaded from
+/// a SEFAZ portal or from the Receita Federal (CSV, XLSX, XLS or ODS).
+///
+/// The first line is the header. Spaces, dots and other separators inside the
+/// key cell are ignored; rows without a valid key (totals, blank lines) are skipped.
+/// Spreadsheets are read from their first sheet.
+pub fn read_lista(path: &Path, opcoes: &OpcoesLista) -> MyResult<Vec<ChaveExterna>> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+
+    let linhas: Vec<Vec<String>> = match extension.as_deref() {
+        Some("xlsx" | "xlsm" | "xls" | "ods") => {
+            let mut workbook = calamine::open_workbook_auto(path)?;
+            let planilha = workbook.worksheet_range_at(0).ok_or_else(|| {
+                MyError::InvalidOptions(format!("'{}' has no sheets", path.display()))
+            })??;
+            planilha
+                .rows()
+                .map(|row| row.iter().map(|cell| cell.to_string()).collect())
+                .collect()
+        }
+        _ => {
+            let file =
+                File::open(path).map_err(|e| MyError::FileReadError(path.to_path_buf(), e))?;
+            let decoder = DecodeReaderBytesBuilder::new()
+                .encoding(Some(opcoes.codificacao))
+                .build(file);
+            let mut reader = csv::ReaderBuilder::new()
+                .delimiter(opcoes.delimitador)
+                .has_headers(false)
+                .flexible(true)
+                .from_reader(decoder);
+            reader
+                .records()
+                .map(|record| Ok(record?.iter().map(str::to_string).collect()))
+                .collect::<MyResult<_>>()?
+        }
+    };
+
+    let Some((cabecalho, registros)) = linhas.split_first() else {
+        return Ok(Vec::new());
+    };
+    let coluna = opcoes.indice_coluna(cabecalho, path)?;
+
+    Ok(registros
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, registro)| {
+            let texto: Vec<u8> = registro
+                .get(coluna)?
+                .bytes()
+                .filter(u8::is_ascii_alphanumeric)
+                .map(|b| b.to_ascii_uppercase())
+                .collect();
+            Some(ChaveExterna {
+                chave: Chave44::from_bytes(&texto)?,
+                // Line of the file: the header is line 1.
+                origem: format!("{}:{}", path.display(), idx + 2),
+            })
+        })
+        .collect())
+}
+
+/// Collects the access keys declared in the EFD files, with the first file
+/// where each one was found, to compare them with an external source.
+///
+/// Only keys classified as `ClasseChave::ChaveAcesso` are kept.
+#[derive(Debug, Clone, Default)]
+pub struct ConciliacaoSink {
+    chaves: BTreeMap<Chave44, PathBuf>,
+}
+
+impl ConciliacaoSink {
+    pub fn new() -> Self {
+        ConciliacaoSink::default()
+    }
+
+    /// Returns the keys found in only one of the EFD files received so far
+    /// and the external source, sorted by key.
+    pub fn conciliar(&self, externas: &[ChaveExterna]) -> Vec<Conciliacao> {
+        let mut chaves: BTreeMap<Chave44, (Option<&PathBuf>, Option<&String>)> = self
+            .chaves
+            .iter()
+            .map(|(&chave, arquivo_efd)| (chave, (Some(arquivo_efd), None)))
+            .collect();
+
+        for externa in externas {
+            let (_, origem) = chaves.entry(externa.chave).or_default();
+            origem.get_or_insert(&externa.origem);
+        }
+
+        chaves
+            .into_iter()
+            .filter_map(|(chave, (arquivo_efd, origem))| {
+                let pendencia = match (arquivo_efd, origem) {
+                    (Some(_), Some(_)) => return None,
+                    (None, _) => Pendencia::AusenteNaEfd,
+                    (Some(_), None) => Pendencia::AusenteNaFonte,
+                };
+                Some(Conciliacao {
+                    chave,
+                    modelo: chave.modelo(),
+                    pendencia,
+                    arquivo_efd: arquivo_efd.cloned(),
+                    origem: origem.cloned(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl KeySink for ConciliacaoSink {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        for &chave in &file_keys.chaves {
+            if chave.classe() == ClasseChave::ChaveAcesso {
+                self.chaves
+                    .entry(chave)
+                    .or_insert_with(|| file_keys.path.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        Ok(())
+    }
+}
+
+/// Writes the reconciliation as CSV, with a header line.
+pub fn write_conciliacao<P>(pendencias: &[Conciliacao], output_file: P) -> MyResult<()>
+where
+    P: AsRef<Path>,
+{
+    let path = output_file.as_ref();
+    let file = File::create(path).map_err(|e| MyError::FileWriteError(path.to_path_buf(), e))?;
+    let mut writer = csv::Writer::from_writer(BufWriter::new(file));
+
+    for pendencia in pendencias {
+        writer.serialize(pendencia)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output conciliacao_tests
+#[cfg(test)]
+mod conciliacao_tests {
+    use super::*;
+    use crate::{test_utils::chave_com_dv, FilterSink, KeyFilter};
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    const DECLARADA: &str = "35250612345678000190550010000001231123456782";
+    const SEM_XML: &str = "352506AB3C5D7E000190550010000001231123456784";
+
+    #[test]
+    fn xml_keys_are_matched_with_the_efd() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+
+        // A procNFe with the protocol, and a CT-e without it, inside a ZIP.
+        let proc_nfe = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <nfeProc xmlns="http://www.portalfiscal.inf.br/nfe" versao="4.00">
+              <NFe><infNFe Id="NFe{DECLARADA}" versao="4.00"/></NFe>
+              <protNFe><infProt><chNFe>{DECLARADA}</chNFe></infProt></protNFe>
+            </nfeProc>"#
+        );
+        fs::write(temp_dir.path().join("nota.xml"), proc_nfe)?;
+
+        let chave_cte = chave_com_dv("3525061234567800019057001000000123112345678")?;
+        let cte = format!(r#"<CTe><infCte Id="CTe{chave_cte}"/></CTe>"#);
+
+        let mut zip = zip::ZipWriter::new(File::create(temp_dir.path().join("ctes.zip"))?);
+        zip.start_file("cte.xml", zip::write::SimpleFileOptions::default())?;
+        zip.write_all(cte.as_bytes())?;
+        zip.finish()?;
+
+        let mut externas = read_xml_dir(temp_dir.path())?;
+        externas.sort_by_key(|externa| externa.chave);
+        assert_eq!(externas.len(), 2);
+        assert!(externas[1].origem.ends_with("ctes.zip/cte.xml"));
+
+        let efd = EfdFileKeys {
+            path: PathBuf::from("PISCOFINS.txt"),
+            chaves: vec![DECLARADA.parse()?, SEM_XML.parse()?],
+            ..Default::default()
+        };
+
+        let mut sink = ConciliacaoSink::new();
+        sink.write_file_keys(&efd)?;
+        let resumo: Vec<(String, Pendencia)> = sink
+            .conciliar(&externas)
+            .into_iter()
+            .map(|conciliacao| (conciliacao.chave.to_string(), conciliacao.pendencia))
+            .collect();
+
+        assert_eq!(
+            resumo,
+            [
+                (chave_cte.to_string(), Pendencia::AusenteNaEfd),
+                (SEM_XML.to_string(), Pendencia::AusenteNaFonte),
+            ]
+        );
+
+        // Only NF-e (model 55): the CT-e is not reconciled.
+        let filtro = KeyFilter {
+            modelos: vec![55],
+            ..Default::default()
+        };
+        let mut sink = FilterSink::new(ConciliacaoSink::new(), filtro.clone());
+        sink.write_file_keys(&efd)?;
+        externas.retain(|externa| filtro.accepts_chave(&externa.chave));
+        let chaves: Vec<String> = sink
+            .into_inner()
+            .conciliar(&externas)
+            .iter()
+            .map(|conciliacao| conciliacao.chave.to_string())
+            .collect();
+        assert_eq!(chaves, [SEM_XML]);
+        Ok(())
+    }
+
+    #[test]
+    fn keys_are_read_from_portal_exports() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("notas_recebidas.csv");
+
+        // WINDOWS-1252 header, formatted key, and a totals line.
+        let mut conteudo = WINDOWS_1252
+            .encode("Emitente;Número;Chave de Acesso\n")
+            .0
+            .into_owned();
+        conteudo.extend_from_slice(
+            b"FORNECEDOR;123;3525 0612 3456 7800 0190 5500 1000 0001 2311 2345 6782\n\
+              TOTAL;;\n",
+        );
+        fs::write(&path, conteudo)?;
+
+        let chaves = read_lista(&path, &OpcoesLista::default())?;
+        assert_eq!(
+            chaves,
+            [ChaveExterna {
+                chave: DECLARADA.parse()?,
+                origem: format!("{}:2", path.display()),
+            }]
+        );
+
+        // The column can also be chosen by its position.
+        let opcoes = OpcoesLista {
+            coluna: Some("2".to_string()),
+            ..Default::default()
+        };
+        assert!(read_lista(&path, &opcoes)?.is_empty());
+        Ok(())
+    }
+}
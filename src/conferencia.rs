@@ -0,0 +1,231 @@
+use crate::{
+    error::{MyError, MyResult},
+    DocumentoChave, EfdFileKeys, KeySink, Participante,
+};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// A field of a C100/D100/C500 record that disagrees with the cited key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Divergencia {
+    pub arquivo: PathBuf,
+    pub linha: usize,
+    pub registro: String,
+    pub chave: String,
+    /// Compared field: "modelo", "serie", "numero", "aamm" or "cnpj".
+    pub campo: &'static str,
+    /// Value encoded in the key.
+    pub na_chave: String,
+    /// Value informed in the record (or in the 0150 participant, for "cnpj").
+    pub no_registro: String,
+}
+
+/// Parses a numeric field. Empty and non-numeric fields are not compared.
+fn numero(campo: &str) -> Option<u64> {
+    campo.parse().ok()
+}
+
+/// Compares the key of a document with the other fields of its record.
+///
+/// For documents issued by third parties, the issuer CNPJ of the key is compared
+/// with the CNPJ (or CPF) of the 0150 participant, when found.
+/// Empty and non-numeric fields are not compared, except the CNPJ.
+pub fn conferir_documento(documento: &DocumentoChave) -> Vec<(&'static str, String, String)> {
+    let chave = documento.chave;
+    let mut divergencias = Vec::new();
+    let mut conferir = |campo: &'static str, na_chave: u64, no_registro: &str| {
+        if numero(no_registro).is_some_and(|valor| valor != na_chave) {
+            divergencias.push((campo, na_chave.to_string(), no_registro.to_string()));
+        }
+    };
+
+    conferir("modelo", chave.modelo().into(), &documento.cod_mod);
+    conferir("serie", chave.serie().into(), &documento.ser);
+    conferir("numero", chave.numero().into(), &documento.num_doc);
+
+    // DT_DOC is ddmmaaaa; the key has aamm.
+    if let Some(aamm) = documento
+        .dt_doc
+        .get(6..8)
+        .zip(documento.dt_doc.get(2..4))
+        .map(|(aa, mm)| format!("{aa}{mm}"))
+    {
+        if aamm != chave.aamm() {
+            divergencias.push(("aamm", chave.aamm(), documento.dt_doc.clone()));
+        }
+    }
+
+    if documento.emissao_terceiros() {
+        let cnpj = documento
+            .participante
+            .as_ref()
+            .and_then(Participante::cnpj_da_chave);
+
+        if let Some(cnpj) = cnpj.filter(|cnpj| *cnpj != chave.cnpj()) {
+            divergencias.push(("cnpj", chave.cnpj(), cnpj));
+        }
+    }
+
+    divergencias
+}
+
+/// Divergences between the keys of the C100/D100/C500 records of a file
+/// and the other fields of the same records, in line order.
+pub fn conferir_arquivo(file_keys: &EfdFileKeys) -> Vec<Divergencia> {
+    let mut documentos: Vec<&DocumentoChave> = file_keys.documentos.iter().collect();
+    documentos.sort_by_key(|documento| documento.linha);
+
+    documentos
+        .into_iter()
+        .flat_map(|documento| {
+            conferir_documento(documento)
+                .into_iter()
+                .map(|(campo, na_chave, no_registro)| Divergencia {
+                    arquivo: file_keys.path.clone(),
+                    linha: documento.linha,
+                    registro: documento.registro.clone(),
+                    chave: documento.chave.to_string(),
+                    campo,
+                    na_chave,
+                    no_registro,
+                })
+        })
+        .collect()
+}
+
+/// Writes the divergences of each file (see `conferir_arquivo`) as CSV,
+/// as soon as the file is processed.
+pub struct ConferenciaReportSink<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: Write> ConferenciaReportSink<W> {
+    pub fn new(writer: W) -> Self {
+        ConferenciaReportSink {
+            writer: csv::Writer::from_writer(writer),
+        }
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> MyResult<W> {
+        self.writer
+            .into_inner()
+            .map_err(|e| MyError::IoError(io::Error::other(e.to_string())))
+    }
+}
+
+impl ConferenciaReportSink<BufWriter<File>> {
+    /// Creates the report file.
+    pub fn create(path: &Path) -> MyResult<Self> {
+        let file =
+            File::create(path).map_err(|e| MyError::FileWriteError(path.to_path_buf(), e))?;
+        Ok(ConferenciaReportSink::new(BufWriter::new(file)))
+    }
+}
+
+impl<W: Write> KeySink for ConferenciaReportSink<W> {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        for divergencia in conferir_arquivo(file_keys) {
+            self.writer.serialize(divergencia)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output conferencia_tests
+#[cfg(test)]
+mod conferencia_tests {
+    use super::*;
+    use crate::read_efd_file;
+    use std::fs;
+    use tempfile::tempdir;
+
+    /// Line, field, value in the key and value in the record of each divergence.
+    fn resumo(divergencias: &[Divergencia]) -> Vec<(usize, &str, &str, &str)> {
+        divergencias
+            .iter()
+            .map(|d| {
+                (
+                    d.linha,
+                    d.campo,
+                    d.na_chave.as_str(),
+                    d.no_registro.as_str(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn records_are_compared_with_their_keys() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("PISCOFINS_CONFERENCIA.txt");
+
+        // Key: SP, 2506, CNPJ 12345678000190, model 55, series 1, number 123.
+        let chave = "35250612345678000190550010000001231123456782";
+        fs::write(
+            &path,
+            format!(
+                "|0000|006|0|||01062025|30062025|EMPRESA|99999999000191|SP|\n\
+                 |0150|F1|FORNECEDOR|01058|12345678000190||||\n\
+                 |0150|F2|OUTRO|01058|11111111000111||||\n\
+                 |C100|0|1|F1|55|00|1|123|{chave}|15062025|\n\
+                 |C100|0|1|F2|55|00|2|124|{chave}|15072025|\n\
+                 |D100|0|1|F1|57|00|1||123|{chave}|15062025|\n\
+                 |9999|7|\n"
+            ),
+        )?;
+
+        let mut sink = ConferenciaReportSink::new(Vec::new());
+        sink.write_file_keys(&read_efd_file(&path)?)?;
+        sink.finish()?;
+        let csv = String::from_utf8(sink.into_inner()?).unwrap_or_default();
+        assert_eq!(csv.lines().count(), 6);
+
+        assert_eq!(
+            resumo(&conferir_arquivo(&read_efd_file(&path)?)),
+            [
+                (5, "serie", "1", "2"),
+                (5, "numero", "123", "124"),
+                (5, "aamm", "2506", "15072025"),
+                (5, "cnpj", "12345678000190", "11111111000111"),
+                (6, "modelo", "55", "57"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn empty_fields_are_not_compared() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("PISCOFINS_SERIE_VAZIA.txt");
+
+        // Series 1 and number 123 in the key; SER and NUM_DOC left blank.
+        let chave = "35250612345678000190550010000001231123456782";
+        fs::write(
+            &path,
+            format!(
+                "|0000|006|0|||01062025|30062025|EMPRESA|99999999000191|SP|\n\
+                 |C100|0|1|F1|55|00||123|{chave}|15062025|\n\
+                 |C100|0|1|F1|55|00|1||{chave}|15062025|\n"
+            ),
+        )?;
+
+        assert!(conferir_arquivo(&read_efd_file(&path)?).is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use crate::{
+    error::{MyError, MyResult},
+    Arguments, LayoutDefinition,
+};
+use std::sync::Arc;
+
+/// Number of bytes in one mebibyte.
+pub const MIB: usize = 1024 * 1024;
+
+/// Options that control how each EFD file is read and scanned for keys.
+#[derive(Debug, Clone, Default)]
+pub struct ExtractionConfig {
+    /// When set, files larger than this size (in bytes) are memory-mapped and
+    /// split at newline boundaries into chunks of about this size,
+    /// which are scanned in parallel.
+    pub mmap_chunk_size: Option<usize>,
+    /// Also recognize keys written in 11 groups of 4 characters separated
+    /// by single spaces, dots or dashes (see `REGEX_CHAVE44_FORMATADA`).
+    pub formatadas: bool,
+    /// Named fields of the records (header, participants and documents).
+    pub layout: Arc<LayoutDefinition>,
+}
+
+impl ExtractionConfig {
+    /// Identifies the options that change the extracted keys, so that results
+    /// cached by another program version or with other options are not reused.
+    ///
+    /// The reading strategy (`mmap_chunk_size`) does not change the results.
+    pub fn cache_signature(&self) -> MyResult<String> {
+        let mut signature = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        if self.formatadas {
+            signature.push_str(" formatadas");
+        }
+        signature.push_str(&format!(" layout={}", self.layout.assinatura()?));
+        Ok(signature)
+    }
+}
+
+impl ExtractionConfig {
+    /// Options of the command line. Reads the `--layout` file, if given.
+    pub fn build(arguments: &Arguments) -> MyResult<Self> {
+        let layout = match &arguments.layout {
+            Some(path) => LayoutDefinition::load(path)?,
+            None => LayoutDefinition::default(),
+        };
+
+        let mmap_chunk_size = if arguments.mmap {
+            let chunk_size = arguments.chunk_size.max(1);
+            let bytes = chunk_size.checked_mul(MIB).ok_or_else(|| {
+                MyError::InvalidOptions(format!("--chunk_size {chunk_size} MiB is too large"))
+            })?;
+            Some(bytes)
+        } else {
+            None
+        };
+
+        Ok(ExtractionConfig {
+            mmap_chunk_size,
+            formatadas: arguments.formatadas,
+            layout: Arc::new(layout),
+        })
+    }
+}
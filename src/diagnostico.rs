@@ -0,0 +1,276 @@
+use crate::{
+    error::{MyError, MyResult},
+    for_each_efd_line, sigla_uf, sort_and_dedup, Chave44, ClasseChave, CHAVE44_LEN, REGEX_CHAVE44,
+};
+use rayon::prelude::*;
+use regex::Regex;
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+use walkdir::DirEntry;
+
+/// Lazy-initialized regex to find runs of 42 to 46 digits (a key with missing
+/// or extra digits). Runs of exactly 44 digits are checked with `REGEX_CHAVE44`.
+static REGEX_QUASE_CHAVE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?x)
+        (?:^|\D)           # Non-capturing group for preceding start/non-digit
+        (\d{42,46})        # Capturing group for the digits
+        (?:$|\D)           # Non-capturing group for trailing end/non-digit
+    ",
+    )
+    .unwrap() // Regex compilation should not fail with a static string
+});
+
+/// Why a sequence is reported as a near-miss key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Problema {
+    /// 42, 43, 45 or 46 digits.
+    TamanhoInvalido,
+    /// 44 characters, but the check digit (position 44) does not match.
+    DvInvalido,
+    /// Valid check digit, but the cUF (positions 1-2) is not an IBGE state code.
+    UfInvalida,
+    /// Valid check digit and UF, but the model (positions 21-22) has no access key.
+    ModeloInvalido,
+}
+
+/// A sequence that is probably a mistyped access key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QuaseChave {
+    pub arquivo: PathBuf,
+    pub linha: usize,
+    /// First field of the line (e.g. "C100").
+    pub registro: String,
+    /// The sequence as found.
+    pub sequencia: String,
+    pub problema: Problema,
+    /// Closest valid access key, reachable by changing, inserting or removing one digit.
+    ///
+    /// For 44-character sequences, changing the check digit is preferred.
+    pub sugestao: Option<Chave44>,
+    /// Number of valid access keys reachable by a single-digit fix.
+    pub candidatas: usize,
+}
+
+/// Valid access keys that differ from `sequencia` by one digit:
+/// one inserted (43 digits), one removed (45 digits) or one changed (44 characters).
+///
+/// For 44 characters, the key with the recomputed check digit (if valid) comes first.
+pub fn correcoes(sequencia: &[u8]) -> Vec<Chave44> {
+    let valida = |bytes: &[u8]| {
+        Chave44::from_bytes(bytes).filter(|chave| chave.classe() == ClasseChave::ChaveAcesso)
+    };
+
+    let mut candidatas: Vec<Chave44> = match sequencia.len() {
+        43 => (0..=sequencia.len())
+            .flat_map(|position| {
+                (b'0'..=b'9').filter_map(move |digit| {
+                    let mut bytes = sequencia.to_vec();
+                    bytes.insert(position, digit);
+                    valida(&bytes)
+                })
+            })
+            .collect(),
+        45 => (0..sequencia.len())
+            .filter_map(|position| {
+                let mut bytes = sequencia.to_vec();
+                bytes.remove(position);
+                valida(&bytes)
+            })
+            .collect(),
+        CHAVE44_LEN => (0..sequencia.len())
+            .flat_map(|position| {
+                (b'0'..=b'9').filter_map(move |digit| {
+                    let mut bytes = sequencia.to_vec();
+                    bytes[position] = digit;
+                    (bytes != sequencia).then(|| valida(&bytes)).flatten()
+                })
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    sort_and_dedup(&mut candidatas);
+
+    // Only the check digit differs: the smallest possible correction.
+    let dv_position = candidatas
+        .iter()
+        .position(|chave| chave.to_ascii()[..CHAVE44_LEN - 1] == sequencia[..CHAVE44_LEN - 1]);
+    if let Some(position) = dv_position.filter(|_| sequencia.len() == CHAVE44_LEN) {
+        let chave = candidatas.remove(position);
+        candidatas.insert(0, chave);
+    }
+
+    candidatas
+}
+
+/// Checks the fields of one line and returns its near-miss sequences.
+pub fn diagnosticar_campos(campos: &[String], linha: usize, arquivo: &Path) -> Vec<QuaseChave> {
+    let registro = campos.first().cloned().unwrap_or_default();
+    let mut quase_chaves = Vec::new();
+
+    let mut report = |sequencia: &str, problema: Problema| {
+        let candidatas = correcoes(sequencia.as_bytes());
+        quase_chaves.push(QuaseChave {
+            arquivo: arquivo.to_path_buf(),
+            linha,
+            registro: registro.clone(),
+            sequencia: sequencia.to_string(),
+            problema,
+            sugestao: candidatas.first().copied(),
+            candidatas: candidatas.len(),
+        });
+    };
+
+    for campo in campos {
+        for capture in REGEX_QUASE_CHAVE.captures_iter(campo) {
+            let Some(sequencia) = capture.get(1).map(|matched| matched.as_str()) else {
+                continue;
+            };
+            if sequencia.len() != CHAVE44_LEN {
+                report(sequencia, Problema::TamanhoInvalido);
+            }
+        }
+
+        for capture in REGEX_CHAVE44.captures_iter(campo) {
+            let Some(sequencia) = capture.get(1).map(|matched| matched.as_str()) else {
+                continue;
+            };
+            let Some(chave) = Chave44::from_bytes(sequencia.as_bytes()) else {
+                continue;
+            };
+
+            // Boleto and collection barcodes are not mistyped keys.
+            let problema = match chave.classe() {
+                ClasseChave::ChaveAcesso | ClasseChave::Boleto | ClasseChave::Arrecadacao => {
+                    continue
+                }
+                ClasseChave::Desconhecida if !chave.dv_valido() => Problema::DvInvalido,
+                ClasseChave::Desconhecida if sigla_uf(chave.codigo_uf()).is_none() => {
+                    Problema::UfInvalida
+                }
+                ClasseChave::Desconhecida => Problema::ModeloInvalido,
+            };
+            report(&chave.to_string(), problema);
+        }
+    }
+
+    quase_chaves
+}
+
+/// Reads an EFD file and returns its near-miss sequences, in line order.
+pub fn diagnose_efd_file(path: &Path) -> MyResult<Vec<QuaseChave>> {
+    let mut quase_chaves = Vec::new();
+
+    for_each_efd_line(path, |linha, campos| {
+        quase_chaves.extend(diagnosticar_campos(campos, linha, path));
+        Ok(())
+    })?;
+
+    Ok(quase_chaves)
+}
+
+/// Diagnoses all EFD files in parallel. Results follow the order of `efd_entries`.
+pub fn diagnose_efd_files(efd_entries: &[DirEntry]) -> MyResult<Vec<QuaseChave>> {
+    let reports = efd_entries
+        .par_iter()
+        .map(|entry| diagnose_efd_file(entry.path()))
+        .collect::<MyResult<Vec<_>>>()?;
+
+    Ok(reports.into_iter().flatten().collect())
+}
+
+/// Writes the near-miss report as CSV, with a header line.
+pub fn write_diagnostico<P>(quase_chaves: &[QuaseChave], output_file: P) -> MyResult<()>
+where
+    P: AsRef<Path>,
+{
+    let path = output_file.as_ref();
+    let file = File::create(path).map_err(|e| MyError::FileWriteError(path.to_path_buf(), e))?;
+    let mut writer = csv::Writer::from_writer(BufWriter::new(file));
+
+    for quase_chave in quase_chaves {
+        writer.serialize(quase_chave)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output diagnostico_tests
+#[cfg(test)]
+mod diagnostico_tests {
+    use super::*;
+    use crate::split_line;
+    use crate::test_utils::chave_com_dv;
+
+    const VALIDA: &str = "35250612345678000190550010000001231123456782";
+
+    fn diagnosticar(line: &str) -> Vec<QuaseChave> {
+        diagnosticar_campos(&split_line(line), 7, Path::new("PISCOFINS.txt"))
+    }
+
+    #[test]
+    fn valid_keys_and_barcodes_are_not_reported() {
+        assert!(diagnosticar(&format!("|C100|0|1|{VALIDA}|")).is_empty());
+        assert!(diagnosticar("|0450|1|00193373700000001000500940144816060680935031|").is_empty());
+    }
+
+    #[test]
+    fn missing_extra_and_wrong_digits() -> MyResult<()> {
+        let valida: Chave44 = VALIDA.parse()?;
+
+        // One digit missing (the "3" of nNF): reinserting it is one of the candidates.
+        let faltando = VALIDA.replacen("0000001231", "000000121", 1);
+        let reported = diagnosticar(&format!("|C100|{faltando}|"));
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].problema, Problema::TamanhoInvalido);
+        assert_eq!(reported[0].linha, 7);
+        assert_eq!(reported[0].registro, "C100");
+        assert!(correcoes(faltando.as_bytes()).contains(&valida));
+
+        // One extra digit.
+        let sobrando = format!("{}9{}", &VALIDA[..30], &VALIDA[30..]);
+        let reported = diagnosticar(&format!("|C100|{sobrando}|"));
+        assert_eq!(reported[0].problema, Problema::TamanhoInvalido);
+        assert!(correcoes(sobrando.as_bytes()).contains(&valida));
+
+        // Wrong check digit: recomputing it is the first suggestion.
+        let dv_errado = format!("{}0", &VALIDA[..43]);
+        let reported = diagnosticar(&format!("|C100|{dv_errado}|"));
+        assert_eq!(reported[0].problema, Problema::DvInvalido);
+        assert_eq!(reported[0].sugestao, Some(valida));
+        assert!(reported[0].candidatas > 1);
+
+        // 42 digits: two fixes away, no suggestion.
+        let reported = diagnosticar(&format!("|C100|{}|", &VALIDA[..42]));
+        assert_eq!(reported[0].problema, Problema::TamanhoInvalido);
+        assert_eq!(reported[0].sugestao, None);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_uf_and_model() -> MyResult<()> {
+        // Model 11.
+        let modelo = chave_com_dv(&format!("{}11{}", &VALIDA[..20], &VALIDA[22..43]))?;
+        let reported = diagnosticar(&format!("|C100|{modelo}|"));
+        assert_eq!(reported[0].problema, Problema::ModeloInvalido);
+
+        // UF 99.
+        let uf = chave_com_dv(&format!("99{}", &VALIDA[2..43]))?;
+        let reported = diagnosticar(&format!("|C100|{uf}|"));
+        assert_eq!(reported[0].problema, Problema::UfInvalida);
+        Ok(())
+    }
+}
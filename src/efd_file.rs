@@ -0,0 +1,202 @@
+use crate::{
+    sort_and_dedup, Chave44, ChaveUrl, ClasseChave, DocumentoChave, DocumentoFiscal, EfdHeader,
+    Emissao, LayoutDefinition, Participante,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem,
+    path::{Path, PathBuf},
+};
+
+/// Fields and keys of one line of an EFD file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EfdLine {
+    /// Number of the line in the file (1-based).
+    pub linha: usize,
+    /// Fields of the line, as returned by `split_line`.
+    pub campos: Vec<String>,
+    /// Keys found in the fields of the line.
+    pub chaves: Vec<Chave44>,
+    /// Keys of `chaves` written in groups separated by spaces, dots or dashes.
+    pub formatadas: Vec<Chave44>,
+    /// Keys of `chaves` found in query URLs.
+    pub urls: Vec<ChaveUrl>,
+}
+
+/// Keys found in one EFD file, sorted and without duplicates,
+/// together with the header ("0000" record) of the file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EfdFileKeys {
+    pub path: PathBuf,
+    pub header: Option<EfdHeader>,
+    pub chaves: Vec<Chave44>,
+    /// Keys of `chaves` found at least once in formatted form
+    /// (groups separated by spaces, dots or dashes), sorted.
+    #[serde(default)]
+    pub formatadas: Vec<Chave44>,
+    /// Keys of `chaves` found in NFC-e QR-code or SEFAZ query URLs, sorted by key,
+    /// with the portal of the first URL of each key.
+    #[serde(default)]
+    pub urls: Vec<ChaveUrl>,
+    /// Keys cited in the key field of C100, D100 and C500 records, sorted by key
+    /// (then by line), with their participant.
+    #[serde(default)]
+    pub documentos: Vec<DocumentoChave>,
+    /// 0150 participants, until the participants of `documentos` are resolved by `finish`.
+    #[serde(skip)]
+    pub(crate) participantes: Vec<Participante>,
+    /// Number of files where each key was found, within the deduplication scope
+    /// (see `ContagemSink`). Keys not counted occur only in this file.
+    #[serde(skip)]
+    pub ocorrencias: BTreeMap<Chave44, usize>,
+}
+
+impl EfdFileKeys {
+    /// Starts an empty result for the file at `path`.
+    pub fn new(path: &Path) -> Self {
+        EfdFileKeys {
+            path: path.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    /// Collects the keys of a line, and the header if the line is the first "0000" record.
+    ///
+    /// 0150 participants and the documents of records with a key field (C100, D100, C500)
+    /// are also collected, with the named fields of `layout` for the version of the file
+    /// (COD_VER). Files of versions unknown to the layout are only scanned for keys.
+    pub(crate) fn add_line(&mut self, line: EfdLine, layout: &LayoutDefinition) {
+        if self.header.is_none() {
+            self.header = EfdHeader::from_campos(&line.campos, layout);
+        }
+
+        let cod_ver = self.header.as_ref().map(|header| header.cod_ver.as_str());
+
+        if let Some(registro) = layout.registro_da_versao(cod_ver, &line.campos) {
+            if let Some(participante) = Participante::from_registro(&registro) {
+                self.participantes.push(participante);
+            } else if let Some(documento) = DocumentoFiscal::from_registro(&registro) {
+                self.documentos
+                    .extend(DocumentoChave::new(&documento, line.linha));
+            }
+        }
+        self.chaves.extend(line.chaves);
+        self.formatadas.extend(line.formatadas);
+        self.urls.extend(line.urls);
+    }
+
+    /// Appends the result of a later part of the same file.
+    pub(crate) fn append(&mut self, other: EfdFileKeys) {
+        if self.header.is_none() {
+            self.header = other.header;
+        }
+        self.chaves.extend(other.chaves);
+        self.formatadas.extend(other.formatadas);
+        self.urls.extend(other.urls);
+        self.documentos.extend(other.documentos);
+        self.participantes.extend(other.participantes);
+    }
+
+    /// Sorts the keys and removes duplicates, once the whole file was read.
+    pub(crate) fn finish(mut self) -> Self {
+        sort_and_dedup(&mut self.chaves);
+        sort_and_dedup(&mut self.formatadas);
+        // Stable sort: the first URL of each key (in file order) is kept.
+        self.urls.sort_by_key(|url| url.chave);
+        self.urls.dedup_by_key(|url| url.chave);
+
+        // The participants are in block 0, which may have been read by another chunk.
+        let participantes: HashMap<String, Participante> = mem::take(&mut self.participantes)
+            .into_iter()
+            .map(|participante| (participante.cod_part.clone(), participante))
+            .collect();
+        for documento in &mut self.documentos {
+            documento.participante = participantes.get(&documento.cod_part).cloned();
+        }
+        self.documentos
+            .sort_by_key(|documento| (documento.chave, documento.linha));
+        self
+    }
+
+    /// COD_VER of the file, if the layout has no field map for it
+    /// (see `LayoutDefinition::conhece_versao`).
+    pub fn versao_desconhecida(&self, layout: &LayoutDefinition) -> Option<&str> {
+        let cod_ver = self.header.as_ref()?.cod_ver.as_str();
+        (!layout.conhece_versao(cod_ver)).then_some(cod_ver)
+    }
+
+    /// Whether the key was issued by the company of the 0000 record or by a third party.
+    ///
+    /// Returns `None` for files without a 0000 record and for sequences that are not access keys.
+    pub fn emissao(&self, chave: &Chave44) -> Option<Emissao> {
+        if chave.classe() != ClasseChave::ChaveAcesso {
+            return None;
+        }
+        self.header.as_ref()?.emissao(chave)
+    }
+
+    /// Documents (C100, D100, C500 records) that cite the key in this file, in line order.
+    pub fn documentos_da_chave(&self, chave: &Chave44) -> &[DocumentoChave] {
+        let start = self.documentos.partition_point(|doc| doc.chave < *chave);
+        let end = self.documentos.partition_point(|doc| doc.chave <= *chave);
+        &self.documentos[start..end]
+    }
+
+    /// Returns `true` if the key was found in formatted form in this file.
+    pub fn is_formatada(&self, chave: &Chave44) -> bool {
+        self.formatadas.binary_search(chave).is_ok()
+    }
+
+    /// Portal of the query URL where the key was found in this file, if any.
+    pub fn portal(&self, chave: &Chave44) -> Option<&str> {
+        self.urls
+            .binary_search_by_key(chave, |url| url.chave)
+            .ok()
+            .map(|idx| self.urls[idx].portal.as_str())
+    }
+
+    /// Number of files where the key was found (at least this one).
+    pub fn ocorrencias(&self, chave: &Chave44) -> usize {
+        self.ocorrencias.get(chave).copied().unwrap_or(1)
+    }
+
+    /// Same file and header, keeping only `chaves` (a subset of the keys of this file).
+    pub fn with_chaves(&self, chaves: Vec<Chave44>) -> EfdFileKeys {
+        let formatadas = chaves
+            .iter()
+            .filter(|chave| self.is_formatada(chave))
+            .copied()
+            .collect();
+        let urls = chaves
+            .iter()
+            .filter_map(|chave| {
+                self.portal(chave).map(|portal| ChaveUrl {
+                    chave: *chave,
+                    portal: portal.to_string(),
+                })
+            })
+            .collect();
+        let documentos = chaves
+            .iter()
+            .flat_map(|chave| self.documentos_da_chave(chave))
+            .cloned()
+            .collect();
+
+        let ocorrencias = chaves
+            .iter()
+            .filter_map(|chave| Some((*chave, *self.ocorrencias.get(chave)?)))
+            .collect();
+
+        EfdFileKeys {
+            path: self.path.clone(),
+            header: self.header.clone(),
+            chaves,
+            formatadas,
+            urls,
+            documentos,
+            participantes: Vec::new(),
+            ocorrencias,
+        }
+    }
+}
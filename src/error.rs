@@ -30,6 +30,14 @@ pub enum MyError {
     #[error("Could not open file '{0}' for writing: {1}")]
     FileWriteError(PathBuf, io::Error),
 
+    /// Error when a text is not a valid 44-character electronic document key.
+    #[error("Invalid 44-character key: '{0}'")]
+    InvalidKey(String),
+
+    /// Error when command-line options cannot be used together.
+    #[error("Invalid options: {0}")]
+    InvalidOptions(String),
+
     /// Error when a specified path does not exist.
     #[error("Path '{0}' not found.")]
     PathNotFound(PathBuf),
@@ -63,6 +71,38 @@ pub enum MyError {
     #[error("Regex error: {0}")]
     RegexError(#[from] regex::Error),
 
+    /// Error while reading or writing CSV data.
+    #[error("CSV error: {0}")]
+    CsvError(#[from] csv::Error),
+
+    /// Error while reading or writing JSON data.
+    #[error("JSON error: {0}")]
+    JsonError(#[from] serde_json::Error),
+
+    /// Error when the output sink stops receiving keys before all files were processed.
+    #[error("Output closed before all EFD files were processed.")]
+    SinkClosed,
+
+    /// Error while reading a layout definition.
+    #[error("TOML error: {0}")]
+    TomlError(#[from] toml::de::Error),
+
+    /// Error when a layout definition is inconsistent (e.g. a version inherits an unknown one).
+    #[error("Invalid layout '{0}': {1}")]
+    InvalidLayout(String, String),
+
+    /// Error while writing a layout definition back as TOML.
+    #[error("TOML error: {0}")]
+    TomlWriteError(#[from] toml::ser::Error),
+
+    /// Error while reading a ZIP archive.
+    #[error("ZIP error: {0}")]
+    ZipError(#[from] zip::result::ZipError),
+
+    /// Error while reading a spreadsheet (XLSX, XLS, ODS).
+    #[error("Spreadsheet error: {0}")]
+    SpreadsheetError(#[from] calamine::Error),
+
     /// Error from `walkdir` crate when traversing directories.
     #[error("Walkdir error: {0}")]
     WalkdirError(#[from] walkdir::Error),
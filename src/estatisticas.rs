@@ -0,0 +1,281 @@
+use crate::{
+    codigos::{nome_modelo, sigla_uf},
+    error::{MyError, MyResult},
+    Chave44, ClasseChave, EfdFileKeys, KeySink,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// Number of keys of an issuer (CNPJ, or "000" followed by the CPF).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Emitente {
+    pub cnpj: String,
+    pub chaves: usize,
+}
+
+/// Totals of the unique keys of a run.
+///
+/// The breakdowns by model, UF, month and issuer only count access keys
+/// (class chave-acesso): the fields of the other sequences mean nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Estatisticas {
+    pub total: usize,
+    /// Sequences by class (chave-acesso, boleto, arrecadacao, desconhecida).
+    pub por_classe: BTreeMap<ClasseChave, usize>,
+    /// Unknown sequences (class desconhecida) whose check digit (position 44) does not
+    /// match the other 43 characters, as in an access key: likely mistyped keys.
+    /// Barcodes (boleto, arrecadacao) have check digits of their own and are not counted.
+    pub dv_invalido: usize,
+    /// Access keys by model, e.g. "55 NF-e".
+    pub por_modelo: BTreeMap<String, usize>,
+    /// Access keys by issuer state, e.g. "SP".
+    pub por_uf: BTreeMap<String, usize>,
+    /// Access keys by year and month of issue (AAMM), e.g. "2506".
+    pub por_aamm: BTreeMap<String, usize>,
+    /// Issuers with the most access keys, in decreasing order.
+    pub emitentes: Vec<Emitente>,
+}
+
+impl Estatisticas {
+    /// Computes the totals of `chaves`, keeping the `top` issuers with the most keys.
+    pub fn from_chaves<'a>(chaves: impl IntoIterator<Item = &'a Chave44>, top: usize) -> Self {
+        let mut estatisticas = Estatisticas::default();
+        let mut emitentes: HashMap<String, usize> = HashMap::new();
+
+        for chave in chaves {
+            let classe = chave.classe();
+            estatisticas.total += 1;
+            *estatisticas.por_classe.entry(classe).or_default() += 1;
+
+            if classe == ClasseChave::Desconhecida && !chave.dv_valido() {
+                estatisticas.dv_invalido += 1;
+            }
+
+            if classe != ClasseChave::ChaveAcesso {
+                continue;
+            }
+
+            let modelo = chave.modelo();
+            let nome = nome_modelo(modelo).unwrap_or_default();
+            *estatisticas
+                .por_modelo
+                .entry(format!("{modelo:02} {nome}"))
+                .or_default() += 1;
+
+            let uf = sigla_uf(chave.codigo_uf()).unwrap_or_default();
+            *estatisticas.por_uf.entry(uf.to_string()).or_default() += 1;
+            *estatisticas.por_aamm.entry(chave.aamm()).or_default() += 1;
+            *emitentes.entry(chave.cnpj()).or_default() += 1;
+        }
+
+        let mut emitentes: Vec<Emitente> = emitentes
+            .into_iter()
+            .map(|(cnpj, chaves)| Emitente { cnpj, chaves })
+            .collect();
+        // Most keys first; ties by CNPJ, so the output does not depend on the hash order.
+        emitentes.sort_by(|a, b| b.chaves.cmp(&a.chaves).then_with(|| a.cnpj.cmp(&b.cnpj)));
+        emitentes.truncate(top);
+        estatisticas.emitentes = emitentes;
+
+        estatisticas
+    }
+
+    /// One row per total: `(dimensao, valor, chaves)`.
+    pub fn linhas(&self) -> Vec<(&'static str, String, usize)> {
+        let mut linhas = vec![("total", String::new(), self.total)];
+
+        linhas.extend(
+            self.por_classe
+                .iter()
+                .map(|(classe, &n)| ("classe", classe.to_string(), n)),
+        );
+        linhas.push(("dv-invalido", String::new(), self.dv_invalido));
+
+        let dimensoes = [
+            ("modelo", &self.por_modelo),
+            ("uf", &self.por_uf),
+            ("aamm", &self.por_aamm),
+        ];
+        for (dimensao, totais) in dimensoes {
+            linhas.extend(
+                totais
+                    .iter()
+                    .map(|(valor, &n)| (dimensao, valor.clone(), n)),
+            );
+        }
+
+        linhas.extend(
+            self.emitentes
+                .iter()
+                .map(|emitente| ("emitente", emitente.cnpj.clone(), emitente.chaves)),
+        );
+        linhas
+    }
+
+    /// Text to print on the terminal: one line per dimension.
+    pub fn summary(&self) -> String {
+        let emitentes = self
+            .emitentes
+            .iter()
+            .map(|emitente| (&emitente.cnpj, &emitente.chaves));
+
+        [
+            format!("total: {} chaves", self.total),
+            format!("por classe: {}", juntar(&self.por_classe)),
+            format!("dv invalido: {}", self.dv_invalido),
+            format!("por modelo: {}", juntar(&self.por_modelo)),
+            format!("por uf: {}", juntar(&self.por_uf)),
+            format!("por aamm: {}", juntar(&self.por_aamm)),
+            format!("maiores emitentes: {}", juntar(emitentes)),
+        ]
+        .join("\n")
+    }
+}
+
+/// Formats totals as `valor: n, valor: n, ...`.
+fn juntar<'a, K: fmt::Display + 'a>(totais: impl IntoIterator<Item = (K, &'a usize)>) -> String {
+    totais
+        .into_iter()
+        .map(|(valor, n)| format!("{valor}: {n}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// One row of the CSV output of `write_estatisticas`.
+#[derive(Serialize)]
+struct LinhaEstatistica<'a> {
+    dimensao: &'a str,
+    valor: &'a str,
+    chaves: usize,
+}
+
+/// Writes the statistics as JSON (`.json` files) or as CSV (any other extension),
+/// with the columns dimensao, valor and chaves.
+pub fn write_estatisticas<P>(estatisticas: &Estatisticas, output_file: P) -> MyResult<()>
+where
+    P: AsRef<Path>,
+{
+    let path = output_file.as_ref();
+    let file = File::create(path).map_err(|e| MyError::FileWriteError(path.to_path_buf(), e))?;
+    let mut writer = BufWriter::new(file);
+
+    let json = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+
+    if json {
+        serde_json::to_writer_pretty(&mut writer, estatisticas)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        return Ok(());
+    }
+
+    let mut writer = csv::Writer::from_writer(writer);
+    for (dimensao, valor, chaves) in estatisticas.linhas() {
+        writer.serialize(LinhaEstatistica {
+            dimensao,
+            valor: &valor,
+            chaves,
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Collects the unique keys of all files, for `Estatisticas`.
+#[derive(Debug, Clone, Default)]
+pub struct EstatisticasSink {
+    chaves: HashSet<Chave44>,
+}
+
+impl EstatisticasSink {
+    pub fn new() -> Self {
+        EstatisticasSink::default()
+    }
+
+    /// Totals of the keys received so far, with the `top` issuers with the most keys.
+    pub fn estatisticas(&self, top: usize) -> Estatisticas {
+        Estatisticas::from_chaves(&self.chaves, top)
+    }
+}
+
+impl KeySink for EstatisticasSink {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        self.chaves.extend(&file_keys.chaves);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        Ok(())
+    }
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output estatisticas_tests
+#[cfg(test)]
+mod estatisticas_tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn keys_are_totalled_by_component() -> MyResult<()> {
+        let chaves: Vec<Chave44> = [
+            "35250612345678000190550010000001231123456782",
+            "352506AB3C5D7E000190550010000001231123456784",
+            "35250612345678000190550010000001241123456789",
+        ]
+        .iter()
+        .map(|chave| chave.parse())
+        .collect::<MyResult<_>>()?;
+
+        let estatisticas = Estatisticas::from_chaves(&chaves, 1);
+        assert_eq!(estatisticas.total, 3);
+        assert_eq!(estatisticas.dv_invalido, 1);
+        assert_eq!(
+            estatisticas.por_classe.get(&ClasseChave::Desconhecida),
+            Some(&1)
+        );
+        assert_eq!(estatisticas.por_modelo.get("55 NF-e"), Some(&2));
+        assert_eq!(estatisticas.por_uf.get("SP"), Some(&2));
+        assert_eq!(estatisticas.por_aamm.get("2506"), Some(&2));
+        // Top 1 issuer; ties are sorted by CNPJ.
+        assert_eq!(
+            estatisticas.emitentes,
+            [Emitente {
+                cnpj: "12345678000190".to_string(),
+                chaves: 1
+            }]
+        );
+
+        // A barcode is not an access key with a wrong check digit.
+        let arrecadacao: Chave44 = "81234567890123456789012345678901234567890123".parse()?;
+        assert_eq!(arrecadacao.classe(), ClasseChave::Arrecadacao);
+        assert!(!arrecadacao.dv_valido());
+        assert_eq!(Estatisticas::from_chaves(&[arrecadacao], 1).dv_invalido, 0);
+
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("estatisticas.csv");
+        write_estatisticas(&estatisticas, &path)?;
+        let csv = fs::read_to_string(&path)?;
+        assert!(csv.starts_with("dimensao,valor,chaves\ntotal,,3\n"));
+        assert!(csv.contains("modelo,55 NF-e,2\n"));
+
+        let path = temp_dir.path().join("estatisticas.json");
+        write_estatisticas(&estatisticas, &path)?;
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        assert_eq!(json["por_uf"]["SP"], 2);
+        assert_eq!(json["por_classe"]["chave-acesso"], 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+use crate::{
+    error::{MyError, MyResult},
+    read_xml_dir_with, Chave44, EfdFileKeys, KeySink, Situacao,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Event types (tpEvento) that cancel a document.
+///
+/// 110111: cancelamento; 110112: cancelamento por substituição (NFC-e).
+pub const EVENTOS_CANCELAMENTO: [&str; 2] = ["110111", "110112"];
+
+/// Event type of the correction letter (carta de correção, CC-e).
+pub const EVENTO_CARTA_CORRECAO: &str = "110110";
+
+/// Status codes (cStat) of registered events.
+const EVENTO_REGISTRADO: [&str; 3] = ["135", "136", "155"];
+
+/// An NF-e, CT-e or MDF-e event (`procEventoNFe`, `procEventoCTe`, ...).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventoXml {
+    /// Key of the document the event refers to.
+    pub chave: Chave44,
+    /// Event type (tpEvento).
+    pub tp_evento: String,
+    /// File of the event (`arquivo.zip/evento.xml` for XMLs inside ZIPs).
+    pub origem: String,
+}
+
+impl EventoXml {
+    /// Returns `true` if the event cancels the document.
+    pub fn cancelamento(&self) -> bool {
+        EVENTOS_CANCELAMENTO.contains(&self.tp_evento.as_str())
+    }
+}
+
+/// Returns the key (chNFe, chCTe or chMDFe) and the type (tpEvento) of an event XML.
+///
+/// Events rejected by SEFAZ (a `retEvento` whose cStat is not 135, 136 or 155) are ignored.
+pub fn evento_do_xml(xml: &str) -> Option<(Chave44, String)> {
+    let documento = roxmltree::Document::parse(xml).ok()?;
+    let elementos = || documento.descendants().filter(|node| node.is_element());
+    let texto = |nome: &str| {
+        elementos()
+            .find(|node| node.tag_name().name() == nome)
+            .and_then(|node| node.text())
+            .map(str::trim)
+    };
+
+    if texto("cStat").is_some_and(|c_stat| !EVENTO_REGISTRADO.contains(&c_stat)) {
+        return None;
+    }
+
+    let chave = ["chNFe", "chCTe", "chMDFe"]
+        .into_iter()
+        .find_map(|nome| Chave44::from_bytes(texto(nome)?.as_bytes()))?;
+
+    Some((chave, texto("tpEvento")?.to_string()))
+}
+
+/// Searches a directory recursively for event XMLs (also inside ZIP files).
+/// Other XMLs are ignored.
+pub fn read_event_dir(dir: &Path) -> MyResult<Vec<EventoXml>> {
+    read_xml_dir_with(dir, |xml, origem| {
+        let (chave, tp_evento) = evento_do_xml(xml)?;
+        Some(EventoXml {
+            chave,
+            tp_evento,
+            origem,
+        })
+    })
+}
+
+/// A C100/D100/C500 document whose key was cancelled, declared with a COD_SIT
+/// other than cancelled (02 or 03).
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct DocumentoCancelado {
+    pub arquivo: PathBuf,
+    pub linha: usize,
+    pub registro: String,
+    pub chave: String,
+    pub cod_sit: String,
+    /// File of the cancellation event.
+    pub evento: String,
+}
+
+/// Writes the documents of the EFD files cancelled by an event but declared
+/// as not cancelled, as CSV sorted by file and line.
+///
+/// Taking PIS/COFINS credits on cancelled documents is a recurring fiscal risk.
+pub struct CanceladasReportSink<W: Write> {
+    writer: W,
+    /// Cancelled keys, with the file of the cancellation event.
+    canceladas: HashMap<Chave44, String>,
+    linhas: Vec<DocumentoCancelado>,
+}
+
+impl<W: Write> CanceladasReportSink<W> {
+    /// Only the cancellation events of `eventos` are considered.
+    pub fn new(writer: W, eventos: Vec<EventoXml>) -> Self {
+        let canceladas = eventos
+            .into_iter()
+            .filter(EventoXml::cancelamento)
+            .map(|evento| (evento.chave, evento.origem))
+            .collect();
+
+        CanceladasReportSink {
+            writer,
+            canceladas,
+            linhas: Vec::new(),
+        }
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl CanceladasReportSink<BufWriter<File>> {
+    /// Creates the report file.
+    pub fn create(path: &Path, eventos: Vec<EventoXml>) -> MyResult<Self> {
+        let file =
+            File::create(path).map_err(|e| MyError::FileWriteError(path.to_path_buf(), e))?;
+        Ok(CanceladasReportSink::new(BufWriter::new(file), eventos))
+    }
+}
+
+impl<W: Write> KeySink for CanceladasReportSink<W> {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        for documento in &file_keys.documentos {
+            let Some(evento) = self.canceladas.get(&documento.chave) else {
+                continue;
+            };
+            if documento.situacao().is_some_and(Situacao::cancelado) {
+                continue;
+            }
+
+            self.linhas.push(DocumentoCancelado {
+                arquivo: file_keys.path.clone(),
+                linha: documento.linha,
+                registro: documento.registro.clone(),
+                chave: documento.chave.to_string(),
+                cod_sit: documento.cod_sit.clone(),
+                evento: evento.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        self.linhas.sort();
+        let mut writer = csv::Writer::from_writer(&mut self.writer);
+        for linha in self.linhas.drain(..) {
+            writer.serialize(linha)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output evento_tests
+#[cfg(test)]
+mod evento_tests {
+    use super::*;
+    use crate::{chave_do_xml, read_efd_file};
+    use std::fs;
+    use tempfile::tempdir;
+
+    const CANCELADA: &str = "35250612345678000190550010000001231123456782";
+    const CORRIGIDA: &str = "352506AB3C5D7E000190550010000001231123456784";
+
+    fn proc_evento(chave: &str, tp_evento: &str, c_stat: &str) -> String {
+        format!(
+            r#"<procEventoNFe xmlns="http://www.portalfiscal.inf.br/nfe" versao="1.00">
+              <evento><infEvento Id="ID{tp_evento}{chave}01">
+                <chNFe>{chave}</chNFe><tpEvento>{tp_evento}</tpEvento>
+              </infEvento></evento>
+              <retEvento><infEvento><cStat>{c_stat}</cStat></infEvento></retEvento>
+            </procEventoNFe>"#
+        )
+    }
+
+    #[test]
+    fn cancelled_keys_declared_as_regular_are_flagged() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let xml_dir = temp_dir.path().join("eventos");
+        fs::create_dir(&xml_dir)?;
+        fs::write(
+            xml_dir.join("cancelamento.xml"),
+            proc_evento(CANCELADA, "110111", "135"),
+        )?;
+        fs::write(
+            xml_dir.join("cce.xml"),
+            proc_evento(CORRIGIDA, "110110", "135"),
+        )?;
+        // Rejected cancellation of the corrected key: ignored.
+        fs::write(
+            xml_dir.join("rejeitado.xml"),
+            proc_evento(CORRIGIDA, "110111", "573"),
+        )?;
+
+        // Events are not documents.
+        assert_eq!(chave_do_xml(&proc_evento(CANCELADA, "110111", "135")), None);
+
+        let mut eventos = read_event_dir(&xml_dir)?;
+        eventos.sort_by_key(|evento| evento.tp_evento.clone());
+        assert_eq!(eventos.len(), 2);
+        assert!(!eventos[0].cancelamento());
+        assert!(eventos[1].cancelamento());
+
+        let path = temp_dir.path().join("PISCOFINS_CANCELADAS.txt");
+        fs::write(
+            &path,
+            format!(
+                "|C100|0|1|F1|55|00|1|123|{CANCELADA}|15062025|\n\
+                 |C100|0|1|F1|55|02|1|123|{CANCELADA}|15062025|\n\
+                 |C100|0|1|F1|55|00|1|123|{CORRIGIDA}|15062025|\n"
+            ),
+        )?;
+
+        let mut report = CanceladasReportSink::new(Vec::new(), eventos);
+        report.write_file_keys(&read_efd_file(&path)?)?;
+        report.finish()?;
+
+        let csv = String::from_utf8(report.into_inner()).unwrap_or_default();
+        let linhas: Vec<&str> = csv.lines().collect();
+        assert_eq!(linhas.len(), 2);
+        assert_eq!(linhas[0], "arquivo,linha,registro,chave,cod_sit,evento");
+        assert!(linhas[1].contains(&format!(",1,C100,{CANCELADA},00,")));
+        assert!(linhas[1].ends_with("cancelamento.xml"));
+        Ok(())
+    }
+}
@@ -0,0 +1,418 @@
+use crate::{
+    error::MyResult, Chave44, ClasseChave, DocumentoChave, EfdFileKeys, Emissao, KeySink, Operacao,
+    Situacao,
+};
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+
+/// Selects which of the 44-character sequences found are written to the outputs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyFilter {
+    /// Classes of sequences to keep.
+    pub classes: Vec<ClasseChave>,
+    /// Keep only keys cited by documents with one of these IND_OPER (all if empty).
+    pub operacoes: Vec<Operacao>,
+    /// Keep only keys cited by documents with one of these COD_SIT (all if empty).
+    pub situacoes: Vec<Situacao>,
+    /// Keep only keys of these models (mod), e.g. 55 (all if empty).
+    pub modelos: Vec<u8>,
+    /// Keep only keys of these issuer states, as IBGE codes (all if empty).
+    pub ufs: Vec<u8>,
+    /// Keep only keys issued from this month on (AAMM, e.g. "2501").
+    pub desde: Option<String>,
+    /// Keep only keys issued up to this month (AAMM, e.g. "2506").
+    pub ate: Option<String>,
+    /// Keep only keys of these issuer CNPJs, 14 characters (all if empty).
+    pub cnpjs: Vec<String>,
+    /// Keep only keys whose issuer CNPJ starts with one of these roots, 8 characters (all if empty).
+    pub cnpj_raizes: Vec<String>,
+    /// Keep only keys with these types of issue (tpEmis), e.g. 1 for normal (all if empty).
+    pub tp_emis: Vec<u8>,
+    /// Keep only keys issued by the declaring company (propria) or by third parties (terceiros).
+    pub emissao: Option<Emissao>,
+}
+
+impl Default for KeyFilter {
+    /// Keeps every sequence.
+    fn default() -> Self {
+        KeyFilter {
+            classes: ClasseChave::value_variants().to_vec(),
+            operacoes: Vec::new(),
+            situacoes: Vec::new(),
+            modelos: Vec::new(),
+            ufs: Vec::new(),
+            desde: None,
+            ate: None,
+            cnpjs: Vec::new(),
+            cnpj_raizes: Vec::new(),
+            tp_emis: Vec::new(),
+            emissao: None,
+        }
+    }
+}
+
+impl KeyFilter {
+    /// Returns `true` if the key, found in `file_keys`, must be written to the outputs.
+    pub fn accepts(&self, chave: &Chave44, file_keys: &EfdFileKeys) -> bool {
+        self.motivo_descarte(chave, file_keys).is_none()
+    }
+
+    /// Returns `true` if the key passes the filters on the key itself (class, model,
+    /// UF, period, issuer, type of issue), for keys found outside the EFD files.
+    pub fn accepts_chave(&self, chave: &Chave44) -> bool {
+        self.classes.contains(&chave.classe()) && self.motivo_componente(chave).is_none()
+    }
+
+    /// Name of the first filter that rejects the key, if any.
+    ///
+    /// With a document filter (operation or situation), the key must be cited by
+    /// at least one document of the file that passes it: keys found only in free text
+    /// are discarded. With the issuer filter, keys of files without a 0000 record are discarded.
+    pub fn motivo_descarte(
+        &self,
+        chave: &Chave44,
+        file_keys: &EfdFileKeys,
+    ) -> Option<&'static str> {
+        if !self.classes.contains(&chave.classe()) {
+            return Some("classe");
+        }
+
+        if let Some(motivo) = self.motivo_componente(chave) {
+            return Some(motivo);
+        }
+
+        if self
+            .emissao
+            .is_some_and(|emissao| file_keys.emissao(chave) != Some(emissao))
+        {
+            return Some("emissao");
+        }
+
+        let mut documentos = file_keys.documentos_da_chave(chave).iter();
+
+        if !self.operacoes.is_empty() {
+            let aceitos: Vec<&DocumentoChave> = documentos
+                .filter(|documento| self.accepts_operacao(documento))
+                .collect();
+            if aceitos.is_empty() {
+                return Some("operacao");
+            }
+            if !aceitos
+                .iter()
+                .any(|documento| self.accepts_situacao(documento))
+            {
+                return Some("situacao");
+            }
+        } else if !self.situacoes.is_empty()
+            && !documentos.any(|documento| self.accepts_situacao(documento))
+        {
+            return Some("situacao");
+        }
+
+        None
+    }
+
+    /// Name of the first filter on the fields of the key (model, UF, period,
+    /// issuer, type of issue) that rejects it, if any.
+    fn motivo_componente(&self, chave: &Chave44) -> Option<&'static str> {
+        let aceita = |valores: &[u8], valor: u8| valores.is_empty() || valores.contains(&valor);
+
+        if !aceita(&self.modelos, chave.modelo()) {
+            return Some("modelo");
+        }
+        if !aceita(&self.ufs, chave.codigo_uf()) {
+            return Some("uf");
+        }
+
+        if self.desde.is_some() || self.ate.is_some() {
+            let aamm = chave.aamm();
+            let antes = self.desde.as_ref().is_some_and(|desde| aamm < *desde);
+            let depois = self.ate.as_ref().is_some_and(|ate| aamm > *ate);
+            if antes || depois {
+                return Some("periodo");
+            }
+        }
+
+        if !self.cnpjs.is_empty() || !self.cnpj_raizes.is_empty() {
+            let cnpj = chave.cnpj();
+            if !self.cnpjs.is_empty() && !self.cnpjs.contains(&cnpj) {
+                return Some("cnpj");
+            }
+            if !self.cnpj_raizes.is_empty()
+                && !self.cnpj_raizes.iter().any(|raiz| cnpj.starts_with(raiz))
+            {
+                return Some("cnpj-raiz");
+            }
+        }
+
+        if !aceita(&self.tp_emis, chave.tp_emis()) {
+            return Some("tp-emis");
+        }
+
+        None
+    }
+
+    /// Returns `true` if the document passes the operation and situation filters.
+    pub fn accepts_documento(&self, documento: &DocumentoChave) -> bool {
+        self.accepts_operacao(documento) && self.accepts_situacao(documento)
+    }
+
+    fn accepts_operacao(&self, documento: &DocumentoChave) -> bool {
+        self.operacoes.is_empty()
+            || documento
+                .operacao()
+                .is_some_and(|operacao| self.operacoes.contains(&operacao))
+    }
+
+    fn accepts_situacao(&self, documento: &DocumentoChave) -> bool {
+        self.situacoes.is_empty()
+            || documento
+                .situacao()
+                .is_some_and(|situacao| self.situacoes.contains(&situacao))
+    }
+}
+
+/// Number of keys kept and discarded by a `FilterSink`, by class,
+/// and number of keys discarded by each filter.
+///
+/// Keys are counted once per file where they were found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilterStats {
+    pub mantidas: BTreeMap<ClasseChave, usize>,
+    pub descartadas: BTreeMap<ClasseChave, usize>,
+    /// Discarded keys by the first filter that rejected them (see `KeyFilter::motivo_descarte`).
+    pub motivos: BTreeMap<&'static str, usize>,
+}
+
+impl FilterStats {
+    /// One line per class: `<classe>: <n> mantidas, <n> descartadas`,
+    /// then one line per filter: `descartadas por <filtro>: <n>`.
+    pub fn summary(&self) -> String {
+        let classes = ClasseChave::value_variants().iter().filter_map(|classe| {
+            let mantidas = self.mantidas.get(classe).copied().unwrap_or(0);
+            let descartadas = self.descartadas.get(classe).copied().unwrap_or(0);
+            (mantidas + descartadas > 0)
+                .then(|| format!("{classe}: {mantidas} mantidas, {descartadas} descartadas"))
+        });
+
+        let motivos = self
+            .motivos
+            .iter()
+            .map(|(motivo, n)| format!("descartadas por {motivo}: {n}"));
+
+        classes.chain(motivos).collect::<Vec<_>>().join("\n")
+    }
+}
+
+/// Forwards to the inner sink only the keys accepted by the filter,
+/// and only the documents that pass the document filters.
+pub struct FilterSink<S: KeySink> {
+    inner: S,
+    filter: KeyFilter,
+    stats: FilterStats,
+}
+
+impl<S: KeySink> FilterSink<S> {
+    pub fn new(inner: S, filter: KeyFilter) -> Self {
+        FilterSink {
+            inner,
+            filter,
+            stats: FilterStats::default(),
+        }
+    }
+
+    /// Keys kept and discarded so far.
+    pub fn stats(&self) -> &FilterStats {
+        &self.stats
+    }
+
+    /// Returns the inner sink.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: KeySink> KeySink for FilterSink<S> {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        let mut chaves = Vec::with_capacity(file_keys.chaves.len());
+
+        for &chave in &file_keys.chaves {
+            let classe = chave.classe();
+            let motivo = self.filter.motivo_descarte(&chave, file_keys);
+
+            let counts = match motivo {
+                None => {
+                    chaves.push(chave);
+                    &mut self.stats.mantidas
+                }
+                Some(motivo) => {
+                    *self.stats.motivos.entry(motivo).or_default() += 1;
+                    &mut self.stats.descartadas
+                }
+            };
+            *counts.entry(classe).or_default() += 1;
+        }
+
+        let mut filtered = file_keys.with_chaves(chaves);
+        filtered
+            .documentos
+            .retain(|documento| self.filter.accepts_documento(documento));
+
+        self.inner.write_file_keys(&filtered)
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        self.inner.finish()
+    }
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output filtro_tests
+#[cfg(test)]
+mod filtro_tests {
+    use super::*;
+    use crate::TextSink;
+    use std::path::PathBuf;
+
+    #[test]
+    fn only_selected_classes_are_written() -> MyResult<()> {
+        let nfe: Chave44 = "35250612345678000190550010000001231123456782".parse()?;
+        let boleto: Chave44 = "00193373700000001000500940144816060680935031".parse()?;
+        let outra: Chave44 = "1".repeat(44).parse()?;
+
+        let file_keys = EfdFileKeys {
+            path: PathBuf::from("PISCOFINS.txt"),
+            header: None,
+            chaves: vec![boleto, outra, nfe],
+            ..Default::default()
+        };
+
+        let filter = KeyFilter {
+            classes: vec![ClasseChave::ChaveAcesso],
+            ..Default::default()
+        };
+        let mut sink = FilterSink::new(TextSink::new(Vec::new()), filter);
+        sink.write_file_keys(&file_keys)?;
+        sink.finish()?;
+
+        assert_eq!(
+            sink.stats().summary(),
+            "chave-acesso: 1 mantidas, 0 descartadas\n\
+             boleto: 0 mantidas, 1 descartadas\n\
+             desconhecida: 0 mantidas, 1 descartadas\n\
+             descartadas por classe: 2"
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&sink.into_inner().into_inner()),
+            format!("{nfe}\n")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn documents_are_filtered_by_operation_and_situation() -> MyResult<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("PISCOFINS_OPER.txt");
+
+        let compra = "35250612345678000190550010000001231123456782";
+        let venda = "35250612345678000190550010000001241123456789";
+        let cancelada = "352506AB3C5D7E000190550010000001231123456784";
+        std::fs::write(
+            &path,
+            format!(
+                "|C100|0|1|F1|55|00|1|123|{compra}|15062025|\n\
+                 |C100|1|0|C1|55|00|1|124|{venda}|15062025|\n\
+                 |C100|0|1|F1|55|02|1|123|{cancelada}|15062025|\n\
+                 |0450|1|{}|\n",
+                "1".repeat(44)
+            ),
+        )?;
+        let file_keys = crate::read_efd_file(&path)?;
+
+        let filter = KeyFilter {
+            operacoes: vec![Operacao::Entrada],
+            situacoes: vec![Situacao::Regular],
+            ..Default::default()
+        };
+        let mut sink = FilterSink::new(TextSink::new(Vec::new()), filter);
+        sink.write_file_keys(&file_keys)?;
+
+        assert_eq!(sink.stats().motivos.get("operacao"), Some(&2));
+        assert_eq!(sink.stats().motivos.get("situacao"), Some(&1));
+        assert_eq!(
+            String::from_utf8_lossy(&sink.into_inner().into_inner()),
+            format!("{compra}\n")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keys_are_filtered_by_their_fields() -> MyResult<()> {
+        let sp: Chave44 = "35250612345678000190550010000001231123456782".parse()?;
+        let alfanumerica: Chave44 = "352506AB3C5D7E000190550010000001231123456784".parse()?;
+
+        let filter = KeyFilter {
+            modelos: vec![55],
+            ufs: vec![35],
+            desde: Some("2501".to_string()),
+            ate: Some("2506".to_string()),
+            cnpj_raizes: vec!["12345678".to_string()],
+            ..Default::default()
+        };
+        let sem_header = EfdFileKeys::default();
+        assert!(filter.accepts(&sp, &sem_header));
+        assert_eq!(
+            filter.motivo_descarte(&alfanumerica, &sem_header),
+            Some("cnpj-raiz")
+        );
+
+        let motivo = |filter: KeyFilter| filter.motivo_descarte(&sp, &sem_header);
+        let filter = KeyFilter::default;
+        assert_eq!(
+            motivo(KeyFilter {
+                modelos: vec![65],
+                ..filter()
+            }),
+            Some("modelo")
+        );
+        assert_eq!(
+            motivo(KeyFilter {
+                ufs: vec![33],
+                ..filter()
+            }),
+            Some("uf")
+        );
+        let ate = Some("2505".to_string());
+        assert_eq!(motivo(KeyFilter { ate, ..filter() }), Some("periodo"));
+        let cnpjs = vec!["12345678000271".to_string()];
+        assert_eq!(motivo(KeyFilter { cnpjs, ..filter() }), Some("cnpj"));
+        assert_eq!(
+            motivo(KeyFilter {
+                tp_emis: vec![9],
+                ..filter()
+            }),
+            Some("tp-emis")
+        );
+
+        // Issued by a branch of the declaring company (same CNPJ root).
+        let filial = EfdFileKeys {
+            header: Some(crate::EfdHeader {
+                cnpj: "12345678000271".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let propria = || KeyFilter {
+            emissao: Some(Emissao::Propria),
+            ..filter()
+        };
+        assert!(propria().accepts(&sp, &filial));
+        assert!(!propria().accepts(&alfanumerica, &filial));
+        assert_eq!(propria().motivo_descarte(&sp, &sem_header), Some("emissao"));
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{Chave44, LayoutDefinition, Registro};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Who issued the document of a key, compared with the declaring company.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Emissao {
+    /// Issued by the company itself or one of its branches (same CNPJ root).
+    Propria,
+    /// Issued by a supplier or any other third party.
+    Terceiros,
+}
+
+/// Data of the "0000" record (opening of the file and identification of the company).
+///
+/// EFD Contribuições layout:
+///
+/// `|0000|COD_VER|TIPO_ESCRIT|IND_SIT_ESP|NUM_REC_ANTERIOR|DT_INI|DT_FIN|NOME|CNPJ|UF|...|`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EfdHeader {
+    /// Layout version code (COD_VER).
+    pub cod_ver: String,
+    /// Type of bookkeeping (TIPO_ESCRIT): "0" original, "1" rectifying.
+    pub tipo_escrituracao: String,
+    /// First day of the period (DT_INI), as `ddmmaaaa`.
+    pub dt_ini: String,
+    /// Last day of the period (DT_FIN), as `ddmmaaaa`.
+    pub dt_fin: String,
+    /// Company name (NOME).
+    pub nome: String,
+    /// CNPJ of the declaring company (CNPJ).
+    pub cnpj: String,
+    /// State of the company (UF).
+    pub uf: String,
+}
+
+impl EfdHeader {
+    /// Builds the header from a "0000" record (see `LayoutDefinition`).
+    ///
+    /// Returns `None` for any other record.
+    pub fn from_registro(registro: &Registro) -> Option<Self> {
+        if registro.nome() != "0000" {
+            return None;
+        }
+
+        Some(EfdHeader {
+            cod_ver: registro.texto("COD_VER"),
+            tipo_escrituracao: registro.texto("TIPO_ESCRIT"),
+            dt_ini: registro.texto("DT_INI"),
+            dt_fin: registro.texto("DT_FIN"),
+            nome: registro.texto("NOME"),
+            cnpj: registro.texto("CNPJ"),
+            uf: registro.texto("UF"),
+        })
+    }
+
+    /// Builds the header from the fields of a line (as returned by `split_line`),
+    /// if the line is a "0000" record of `layout`.
+    ///
+    /// The header of a file is its first such line, whatever the reading strategy.
+    pub fn from_campos(campos: &[String], layout: &LayoutDefinition) -> Option<Self> {
+        EfdHeader::from_registro(&layout.registro(campos)?)
+    }
+
+    /// Compares the issuer CNPJ of the key with the CNPJ of the company:
+    /// keys of the same CNPJ root (first 8 characters) were issued by the company or its branches.
+    ///
+    /// Returns `None` if the header has no valid CNPJ.
+    pub fn emissao(&self, chave: &Chave44) -> Option<Emissao> {
+        let raiz = self.cnpj.get(..8).filter(|_| self.cnpj.len() == 14)?;
+        if chave.cnpj()[..8].eq_ignore_ascii_case(raiz) {
+            Some(Emissao::Propria)
+        } else {
+            Some(Emissao::Terceiros)
+        }
+    }
+
+    /// Month of the bookkeeping, as `aaaa-mm` (from DT_INI).
+    pub fn periodo(&self) -> Option<String> {
+        let dt_ini = self.dt_ini.as_str();
+        if dt_ini.len() != 8 || !dt_ini.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        Some(format!("{}-{}", &dt_ini[4..8], &dt_ini[2..4]))
+    }
+}
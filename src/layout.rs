@@ -0,0 +1,408 @@
+use crate::error::{MyError, MyResult};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path, sync::LazyLock};
+
+/// Source of the embedded EFD Contribuições layout.
+const EFD_CONTRIBUICOES_TOML: &str = include_str!("../layouts/efd_contribuicoes.toml");
+
+/// The EFD Contribuições layout, embedded in the program.
+pub static EFD_CONTRIBUICOES: LazyLock<LayoutDefinition> = LazyLock::new(|| {
+    LayoutDefinition::from_toml(EFD_CONTRIBUICOES_TOML).unwrap() // The embedded layout is valid
+});
+
+/// Named fields of the records of a SPED text layout (EFD Contribuições, EFD ICMS/IPI, ECD, ECF...).
+///
+/// Loaded from TOML, so new layouts do not require a new program version:
+///
+/// ```toml
+/// nome = "efd-contribuicoes"
+/// arquivos = ["PISCOFINS*.txt"]
+///
+/// [registros]
+/// "0000" = ["REG", "COD_VER", "TIPO_ESCRIT", ...]
+/// C100 = ["REG", "IND_OPER", "IND_EMIT", ...]
+/// ```
+///
+/// Fields are listed in file order, starting with REG, so that the index of
+/// each name is its index in the output of `split_line`.
+///
+/// `arquivos` lists the names of the files of the layout searched in the directories,
+/// with `*` and `?` wildcards (case-insensitive). Every `.txt` file if absent.
+///
+/// Records whose fields changed between layout versions (COD_VER of the 0000 record)
+/// are redefined in the table of the version. A version can inherit the records
+/// redefined by another one with `herda`:
+///
+/// ```toml
+/// [versoes."002"]
+/// C100 = ["REG", ...]
+///
+/// [versoes."003"]
+/// herda = "002"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayoutDefinition {
+    pub nome: String,
+    /// Patterns of the file names of the layout (see `is_arquivo`).
+    #[serde(default = "todos_os_txt")]
+    pub arquivos: Vec<String>,
+    /// Field names of each record.
+    pub registros: BTreeMap<String, Vec<String>>,
+    /// Known layout versions, with the records that differ from `registros`.
+    ///
+    /// If empty, files of any version are read with `registros`.
+    #[serde(default)]
+    pub versoes: BTreeMap<String, Versao>,
+}
+
+/// The records of a layout version that differ from `LayoutDefinition::registros`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Versao {
+    /// Version whose records are used for those not redefined here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub herda: Option<String>,
+    /// Field names of each redefined record.
+    #[serde(flatten)]
+    pub registros: BTreeMap<String, Vec<String>>,
+}
+
+fn todos_os_txt() -> Vec<String> {
+    vec!["*.txt".to_string()]
+}
+
+impl Default for LayoutDefinition {
+    /// The embedded EFD Contribuições layout.
+    fn default() -> Self {
+        EFD_CONTRIBUICOES.clone()
+    }
+}
+
+impl LayoutDefinition {
+    /// Parses a layout written in TOML.
+    ///
+    /// Fails if a version inherits (`herda`) an unknown version, or itself.
+    pub fn from_toml(text: &str) -> MyResult<Self> {
+        let layout: LayoutDefinition = toml::from_str(text)?;
+
+        for cod_ver in layout.versoes.keys() {
+            let mut atual = cod_ver;
+            for _ in 0..layout.versoes.len() {
+                let Some(base) = &layout.versoes[atual].herda else {
+                    break;
+                };
+                if !layout.versoes.contains_key(base) {
+                    return Err(MyError::InvalidLayout(
+                        layout.nome.clone(),
+                        format!("version {cod_ver} inherits the unknown version {base}"),
+                    ));
+                }
+                atual = base;
+            }
+            if layout.versoes[atual].herda.is_some() {
+                return Err(MyError::InvalidLayout(
+                    layout.nome.clone(),
+                    format!("version {cod_ver} inherits itself"),
+                ));
+            }
+        }
+
+        Ok(layout)
+    }
+
+    /// Reads a layout from a TOML file.
+    pub fn load(path: &Path) -> MyResult<Self> {
+        let text =
+            fs::read_to_string(path).map_err(|e| MyError::FileReadError(path.to_path_buf(), e))?;
+        LayoutDefinition::from_toml(&text)
+    }
+
+    /// Identifies the layout in the cache signature: results read with
+    /// another layout (or another version of the same layout) are not reused.
+    ///
+    /// The blake3 hash of the layout written back as TOML, so comments and
+    /// formatting do not matter and the value is the same for every build.
+    pub fn assinatura(&self) -> MyResult<String> {
+        let toml = toml::to_string(self)?;
+        let hash = blake3::hash(toml.as_bytes());
+        Ok(format!("{}-{}", self.nome, hash.to_hex()))
+    }
+
+    /// Returns `true` if the file name matches one of the patterns of `arquivos`.
+    pub fn is_arquivo(&self, nome: &str) -> bool {
+        let nome = nome.to_uppercase();
+        self.arquivos
+            .iter()
+            .any(|padrao| corresponde(padrao.to_uppercase().as_bytes(), nome.as_bytes()))
+    }
+
+    /// Returns `true` if files of this layout version (COD_VER) have a field map.
+    pub fn conhece_versao(&self, cod_ver: &str) -> bool {
+        self.versoes.is_empty() || self.versoes.contains_key(cod_ver)
+    }
+
+    /// Named view of the fields of a line (as returned by `split_line`),
+    /// with the fields common to all versions.
+    ///
+    /// Returns `None` if the record of the line is not in the layout.
+    pub fn registro<'a>(&'a self, campos: &'a [String]) -> Option<Registro<'a>> {
+        self.registro_da_versao(None, campos)
+    }
+
+    /// Named view of the fields of a line of a file of the layout version `cod_ver`.
+    ///
+    /// Records not redefined for the version, nor for the versions it inherits,
+    /// have the fields common to all versions.
+    /// Returns `None` for versions unknown to the layout: their files are only scanned for keys.
+    pub fn registro_da_versao<'a>(
+        &'a self,
+        cod_ver: Option<&str>,
+        campos: &'a [String],
+    ) -> Option<Registro<'a>> {
+        if cod_ver.is_some_and(|cod_ver| !self.conhece_versao(cod_ver)) {
+            return None;
+        }
+
+        let nome = campos.first()?;
+        let nomes = cod_ver
+            .and_then(|cod_ver| self.registro_herdado(cod_ver, nome))
+            .or_else(|| self.registros.get(nome))?;
+        Some(Registro { campos, nomes })
+    }
+
+    /// Fields of the record `nome` redefined by the version `cod_ver`
+    /// or by the versions it inherits (checked by `from_toml`).
+    fn registro_herdado(&self, cod_ver: &str, nome: &str) -> Option<&Vec<String>> {
+        let mut versao = self.versoes.get(cod_ver)?;
+        for _ in 0..self.versoes.len() {
+            if let Some(nomes) = versao.registros.get(nome) {
+                return Some(nomes);
+            }
+            versao = self.versoes.get(versao.herda.as_ref()?)?;
+        }
+        None
+    }
+}
+
+/// Matches `nome` against a pattern where `*` is any sequence and `?` any single byte.
+fn corresponde(padrao: &[u8], nome: &[u8]) -> bool {
+    match padrao.split_first() {
+        None => nome.is_empty(),
+        Some((b'*', resto)) => (0..=nome.len()).any(|i| corresponde(resto, &nome[i..])),
+        Some((&byte, resto)) => nome
+            .split_first()
+            .is_some_and(|(&n, nome)| (byte == b'?' || byte == n) && corresponde(resto, nome)),
+    }
+}
+
+/// The fields of one line, accessed by name.
+#[derive(Debug, Clone, Copy)]
+pub struct Registro<'a> {
+    campos: &'a [String],
+    nomes: &'a [String],
+}
+
+impl<'a> Registro<'a> {
+    /// Record type (REG), e.g. "C100".
+    pub fn nome(&self) -> &'a str {
+        &self.campos[0]
+    }
+
+    /// Value of a field, if the layout has it and the line is long enough.
+    pub fn campo(&self, nome: &str) -> Option<&'a str> {
+        let idx = self.nomes.iter().position(|n| n == nome)?;
+        self.campos.get(idx).map(String::as_str)
+    }
+
+    /// Value of a field, or an empty string.
+    pub fn texto(&self, nome: &str) -> String {
+        self.campo(nome).unwrap_or_default().to_string()
+    }
+
+    /// Value of the first field whose name starts with `prefixo` (e.g. "CHV_").
+    pub fn campo_com_prefixo(&self, prefixo: &str) -> Option<&'a str> {
+        let idx = self.nomes.iter().position(|n| n.starts_with(prefixo))?;
+        self.campos.get(idx).map(String::as_str)
+    }
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output layout_tests
+#[cfg(test)]
+mod layout_tests {
+    use super::*;
+    use crate::split_line;
+    use clap::Parser;
+    use std::fs;
+
+    #[test]
+    fn fields_are_read_by_name() -> MyResult<()> {
+        // A layout with a single record, as a user could supply for the ECF.
+        let layout = LayoutDefinition::from_toml(
+            r#"
+            nome = "ecf"
+            [registros]
+            Y600 = ["REG", "DT_ALT_SOC", "DT_FIM_SOC", "PAIS", "IND_QUALIF_SOCIO", "CPF_CNPJ"]
+            "#,
+        )?;
+
+        let campos = split_line("|Y600|01012020||105|PJ|12345678000190|");
+        let campo = |nome: &str| layout.registro(&campos).and_then(|r| r.campo(nome));
+        assert_eq!(campo("REG"), Some("Y600"));
+        assert_eq!(campo("CPF_CNPJ"), Some("12345678000190"));
+        assert_eq!(campo("DT_FIM_SOC"), Some(""));
+        assert_eq!(campo("VL_REND"), None);
+
+        assert!(layout.registro(&split_line("|C100|0|")).is_none());
+        assert_ne!(layout.assinatura()?, EFD_CONTRIBUICOES.assinatura()?);
+
+        // Comments and formatting do not change the signature.
+        let reformatada = LayoutDefinition::from_toml(
+            r#"
+            # Same layout.
+            nome = "ecf"
+            registros.Y600 = [
+                "REG", "DT_ALT_SOC", "DT_FIM_SOC", "PAIS", "IND_QUALIF_SOCIO", "CPF_CNPJ",
+            ]
+            "#,
+        )?;
+        assert_eq!(reformatada.assinatura()?, layout.assinatura()?);
+
+        // The embedded layout.
+        let campos = split_line("|C500|F1|06|00|1||123|15062025|");
+        let registro = EFD_CONTRIBUICOES.registro(&campos);
+        assert_eq!(registro.and_then(|r| r.campo("NUM_DOC")), Some("123"));
+        assert_eq!(registro.and_then(|r| r.campo_com_prefixo("CHV_")), None);
+        Ok(())
+    }
+
+    #[test]
+    fn files_are_found_by_the_patterns_of_the_layout() -> MyResult<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let chave = "35250612345678000190550010000001231123456782";
+        let linha = format!("|0000|ECF|{chave}|\n");
+        fs::write(temp_dir.path().join("SPEDECF_2025.txt"), &linha)?;
+        fs::write(temp_dir.path().join("PISCOFINS_2025.txt"), &linha)?;
+        fs::write(temp_dir.path().join("notas.csv"), &linha)?;
+
+        let layout = temp_dir.path().join("ecf.toml");
+        fs::write(
+            &layout,
+            r#"
+            nome = "ecf"
+            arquivos = ["spedecf*.txt"]
+            [registros]
+            "0000" = ["REG", "NOME_ESC", "COD_SCP"]
+            "#,
+        )?;
+
+        let arguments = crate::Arguments::try_parse_from([
+            "efd".as_ref(),
+            "--path".as_ref(),
+            temp_dir.path().as_os_str(),
+            "--layout".as_ref(),
+            layout.as_os_str(),
+        ])
+        .map_err(|e| MyError::InvalidOptions(e.to_string()))?;
+        let config = crate::ExtractionConfig::build(&arguments)?;
+
+        let entries = crate::get_efd_entries(&arguments, &config)?;
+        let nomes: Vec<_> = entries.iter().map(|entry| entry.file_name()).collect();
+        assert_eq!(nomes, ["SPEDECF_2025.txt"]);
+
+        let file_keys = crate::extract_efd_file(&entries[0], &config)?;
+        assert_eq!(file_keys.chaves, [chave.parse()?]);
+
+        // Without the --layout option, only EFD Contribuições files are found.
+        assert!(EFD_CONTRIBUICOES.is_arquivo("piscofins_2025.TXT"));
+        assert!(!EFD_CONTRIBUICOES.is_arquivo("SPEDECF_2025.txt"));
+        assert!(!EFD_CONTRIBUICOES.is_arquivo("PISCOFINS_2025.txt.bak"));
+
+        // Layouts without patterns read every .txt file.
+        let sem_padroes = LayoutDefinition::from_toml("nome = \"x\"\n[registros]\n")?;
+        assert!(sem_padroes.is_arquivo("qualquer.txt"));
+        assert!(!sem_padroes.is_arquivo("qualquer.csv"));
+        Ok(())
+    }
+
+    #[test]
+    fn fields_follow_the_layout_version() -> MyResult<()> {
+        let layout = LayoutDefinition::from_toml(
+            r#"
+            nome = "teste"
+            [registros]
+            C100 = ["REG", "NUM_DOC", "CHV_NFE"]
+            [versoes."001"]
+            C100 = ["REG", "CHV_NFE", "NUM_DOC"]
+            [versoes."002"]
+            [versoes."003"]
+            herda = "001"
+            "#,
+        )?;
+
+        let campos = split_line("|C100|123|KEY|");
+        let chave = |cod_ver| {
+            layout
+                .registro_da_versao(cod_ver, &campos)
+                .and_then(|r| r.campo("CHV_NFE"))
+        };
+        assert_eq!(chave(Some("001")), Some("123"));
+        assert_eq!(chave(Some("002")), Some("KEY"));
+        assert_eq!(chave(Some("003")), Some("123"));
+        assert_eq!(chave(None), Some("KEY"));
+        assert_eq!(chave(Some("009")), None);
+
+        assert!(layout.conhece_versao("002"));
+        assert!(!layout.conhece_versao("009"));
+        assert!(EFD_CONTRIBUICOES.conhece_versao("006"));
+
+        // The inherited versions must exist, without cycles.
+        let herda = |versoes: &str| {
+            LayoutDefinition::from_toml(&format!("nome = \"teste\"\n[registros]\n{versoes}"))
+        };
+        assert!(herda("[versoes.\"002\"]\nherda = \"001\"\n").is_err());
+        assert!(herda("[versoes.\"001\"]\nherda = \"001\"\n").is_err());
+        assert!(
+            herda("[versoes.\"001\"]\nherda = \"002\"\n[versoes.\"002\"]\nherda = \"001\"\n")
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn document_keys_are_read_with_the_layout_of_each_file() -> MyResult<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let nfe = "35250612345678000190550010000001231123456782";
+        let nf3e = "352506AB3C5D7E000190550010000001231123456784";
+
+        // The same C100 and C500 lines in files of two layout versions.
+        let ler = |cod_ver: &str| -> MyResult<Vec<(String, String)>> {
+            let path = temp_dir.path().join(format!("PISCOFINS_{cod_ver}.txt"));
+            fs::write(
+                &path,
+                format!(
+                    "|0000|{cod_ver}|0|||01062025|30062025|EMPRESA|99999999000191|SP|\n\
+                     |C100|0|1|F1|55|00|1|123|{nfe}|15062025|\n\
+                     |C500|F2|66|00|1||456|15062025|15062025|100,00|0||0,65|3,00|{nf3e}|\n"
+                ),
+            )?;
+            let file_keys = crate::read_efd_file(&path)?;
+            assert_eq!(file_keys.chaves.len(), 2);
+            Ok(file_keys
+                .documentos
+                .iter()
+                .map(|doc| (doc.registro.clone(), doc.chave.to_string()))
+                .collect())
+        };
+
+        let c100 = ("C100".to_string(), nfe.to_string());
+        let c500 = ("C500".to_string(), nf3e.to_string());
+        assert_eq!(ler("006")?, [c100.clone(), c500]);
+        // Before version 006, C500 has no key field: the key is only found by the line scan.
+        assert_eq!(ler("005")?, [c100]);
+        Ok(())
+    }
+}
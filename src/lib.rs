@@ -1,9 +1,54 @@
 mod args;
+mod cache;
+mod chave;
+mod codigos;
+mod comparacao;
+mod conciliacao;
+mod conferencia;
+mod config;
+mod diagnostico;
+mod efd_file;
 mod error;
+mod estatisticas;
+mod evento;
+mod filtro;
+mod header;
+mod layout;
+mod mmap;
+mod numeracao;
+mod registro;
+mod relatorio;
+mod sink;
+mod url;
+mod watch;
+
+#[cfg(test)]
+mod test_utils;
 
 pub use self::{
     args::*,
+    cache::*,
+    chave::*,
+    codigos::*,
+    comparacao::*,
+    conciliacao::*,
+    conferencia::*,
+    config::*,
+    diagnostico::*,
+    efd_file::*,
     error::{MyError, MyResult},
+    estatisticas::*,
+    evento::*,
+    filtro::*,
+    header::*,
+    layout::*,
+    mmap::read_efd_file_mmap,
+    numeracao::*,
+    registro::*,
+    relatorio::*,
+    sink::*,
+    url::*,
+    watch::*,
 };
 
 use claudiofsr_lib::open_file;
@@ -12,12 +57,13 @@ use encoding_rs_io::DecodeReaderBytesBuilder;
 use rayon::prelude::*;
 use regex::Regex;
 use std::{
-    collections::BTreeSet,
-    io::{BufRead, BufReader, Read},
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
     ops::Deref,
     path::{Path, PathBuf},
     str,
-    sync::LazyLock,
+    sync::{mpsc, LazyLock},
+    thread,
 };
 use walkdir::{DirEntry, WalkDir};
 
@@ -30,34 +76,71 @@ pub const DELIMITER_CHAR: char = '|';
 /// Lazy-initialized regex to find 44-digit keys.
 /// It looks for 44 digits surrounded by word boundaries or non-digit characters.
 /// The surrounding parts are non-capturing groups.
+///
+/// Positions 7 to 18 (the CNPJ base) also accept letters, so keys issued
+/// by companies with an alphanumeric CNPJ (2026 onwards) are recognized.
 pub static REGEX_CHAVE44: LazyLock<Regex> = LazyLock::new(|| {
     Regex::new(
         r"(?ix)
-        (?:\b|\D) # Non-capturing group for preceding boundary/non-digit
-        (\d{44})  # Capturing group for the 44 digits
-        (?:\b|\D) # Non-capturing group for trailing boundary/non-digit
+        (?:\b|\D)                    # Non-capturing group for preceding boundary/non-digit
+        (\d{6}[0-9A-Z]{12}\d{26})     # Capturing group for the 44 characters
+        (?:\b|\D)                    # Non-capturing group for trailing boundary/non-digit
     ",
     )
     .unwrap() // Regex compilation should not fail with a static string
 });
 
-/// Checa se uma DirEntry é um arquivo EFD Contribuições (arquivo .txt que começa com "PISCOFINS").
-fn is_efd_contribuicoes_file(entry: &DirEntry) -> bool {
+/// Lazy-initialized regex to find formatted keys, as printed in DANFEs and
+/// observations: 11 groups of 4 characters separated by a single space, dot or dash,
+/// e.g. "3525 0612 3456 7800 0190 5500 1000 0001 2311 2345 6782".
+///
+/// As in `REGEX_CHAVE44`, positions 7 to 18 also accept letters.
+/// Use `chave_formatada` to get the canonical key of a match.
+pub static REGEX_CHAVE44_FORMATADA: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?ix)
+        (?:^|[^0-9A-Z])              # Non-capturing group for preceding start/non-alphanumeric
+        (                            # Capturing group for the 11 groups
+            \d{4}               [\ .-]
+            \d{2}[0-9A-Z]{2}     [\ .-]
+            [0-9A-Z]{4}          [\ .-]
+            [0-9A-Z]{4}          [\ .-]
+            [0-9A-Z]{2}\d{2}
+            (?:[\ .-]\d{4}){6}
+        )
+        (?:$|[^0-9A-Z])              # Non-capturing group for trailing end/non-alphanumeric
+    ",
+    )
+    .unwrap() // Regex compilation should not fail with a static string
+});
+
+/// Removes the separators of a key matched by `REGEX_CHAVE44_FORMATADA`.
+pub fn chave_formatada(text: &str) -> Option<Chave44> {
+    let bytes: Vec<u8> = text
+        .bytes()
+        .filter(|byte| byte.is_ascii_alphanumeric())
+        .collect();
+    Chave44::from_bytes(&bytes)
+}
+
+/// Checa se uma DirEntry é um arquivo do layout (nome conforme `LayoutDefinition::arquivos`,
+/// por exemplo "PISCOFINS*.txt" para a EFD Contribuições).
+pub(crate) fn is_efd_file(entry: &DirEntry, layout: &LayoutDefinition) -> bool {
     entry.file_type().is_file() // Deve ser um arquivo
-        && entry
-            .path()
-            .extension()
-            .is_some_and(|ext| ext.eq_ignore_ascii_case("txt")) // Extensão ".txt" (case-insensitive)
         && entry
             .file_name()
             .to_str()
-            .is_some_and(|s| s.to_uppercase().starts_with("PISCOFINS")) // Nome começa com "PISCOFINS" (case-insensitive)
+            .is_some_and(|nome| layout.is_arquivo(nome)) // Nome conforme os padrões do layout (case-insensitive)
 }
 
 /// Retrieves a list of EFD (Escrituração Fiscal Digital) file entries.
 ///
-/// Filters for files with a ".txt" extension and names starting with "PISCOFINS" (case-insensitive).
-pub fn get_efd_entries(arguments: &Arguments) -> MyResult<Vec<DirEntry>> {
+/// Filters for files whose names match the patterns of the layout of `config`
+/// (`PISCOFINS*.txt` for the embedded EFD Contribuições layout, case-insensitive).
+pub fn get_efd_entries(
+    arguments: &Arguments,
+    config: &ExtractionConfig,
+) -> MyResult<Vec<DirEntry>> {
     let dir_path = get_path(&arguments.path)?;
 
     let entries: Vec<DirEntry> = WalkDir::new(dir_path)
@@ -73,7 +156,7 @@ pub fn get_efd_entries(arguments: &Arguments) -> MyResult<Vec<DirEntry>> {
             // Se for Err, ele propaga imediatamente via '?' ao final do 'collect'
             // Se for Ok(entry), ele continua a processar o 'entry'
             entry_result.map(|entry| {
-                if is_efd_contribuicoes_file(&entry) {
+                if is_efd_file(&entry, &config.layout) {
                     Some(entry) // Entrada válida e filtrada
                 } else {
                     None // Entrada válida, mas não passa nos filtros
@@ -101,61 +184,182 @@ pub fn get_path(opt_path: &Option<PathBuf>) -> MyResult<PathBuf> {
 }
 
 /// Processes all EFD (Escrituração Fiscal Digital) file entries in parallel
-/// to extract and combine unique 44-digit keys into a single BTreeSet.
+/// to extract and combine unique 44-digit keys into a single sorted vector.
 ///
 /// This function leverages Rayon for parallel processing and uses a functional
 /// chain of iterators for robust error handling and efficient data aggregation.
 ///
 /// # Arguments
 /// * `efd_entries` - A slice of `DirEntry` references, each representing an EFD file.
+/// * `config` - Options that control how each file is read.
 ///
 /// # Returns
-/// A `MyResult` containing a `BTreeSet<String>` of all unique 44-digit keys
-/// found across all processed files. Returns `Err(MyError)` if any file
-/// processing encounters an error.
-pub fn process_all_efd_files_parallel(efd_entries: &[DirEntry]) -> MyResult<BTreeSet<String>> {
+/// A `MyResult` containing a sorted `Vec<Chave44>` without duplicates, with all
+/// 44-digit keys found across all processed files. Returns `Err(MyError)` if any
+/// file processing encounters an error.
+pub fn process_all_efd_files_parallel(
+    efd_entries: &[DirEntry],
+    config: &ExtractionConfig,
+) -> MyResult<Vec<Chave44>> {
     // 1. Parallelize file processing:
     //    Converts the slice of DirEntry into a parallel iterator.
-    let all_file_keys: BTreeSet<String> = efd_entries
+    let mut all_file_keys: Vec<Chave44> = efd_entries
         .into_par_iter()
         // 2. Map each DirEntry to its extracted keys:
-        //    Calls `extract_keys_from_efd_file` for each DirEntry, returning a `MyResult<BTreeSet<String>>`.
-        //    `extract_keys_from_efd_file` itself handles file I/O, decoding, and key extraction for a single file.
-        .map(extract_keys_from_efd_file)
+        //    Calls `extract_efd_file` for each DirEntry, returning a `MyResult<Vec<Chave44>>`
+        //    already sorted and without duplicates.
+        .map(|entry| extract_efd_file(entry, config).map(|file_keys| file_keys.chaves))
         // 3. Collect results, handling errors:
         //    This `collect` method on an iterator of `Result<T, E>` will:
-        //    - If all items are `Ok`, collect all `BTreeSet<String>` into a `Vec<BTreeSet<String>>`.
+        //    - If all items are `Ok`, collect all `Vec<Chave44>` into a `Vec<Vec<Chave44>>`.
         //    - If any item is `Err`, it immediately returns the first encountered `MyError`,
         //      potentially cancelling further parallel computations.
-        //    The `?` operator then propagates this error or unwraps the `Vec<BTreeSet<String>>`.
-        .collect::<Result<Vec<BTreeSet<String>>, MyError>>()?
-        // At this point, if no errors occurred, we have `Vec<BTreeSet<String>>`.
-        // The subsequent steps aim to flatten this into a single `BTreeSet<String>`.
-        // 4. Convert the `Vec` into a sequential iterator:
-        //    Necessary to use `.flatten()` which operates on `IntoIterator`.
+        //    The `?` operator then propagates this error or unwraps the `Vec<Vec<Chave44>>`.
+        .collect::<Result<Vec<Vec<Chave44>>, MyError>>()?
+        // 4. Flatten the `Vec<Vec<Chave44>>` into a single `Vec<Chave44>`:
+        //    Each key takes only 25 bytes, so the merged vector stays compact.
         .into_iter()
-        // 5. Flatten the `Vec<BTreeSet<String>>` into an iterator of `String`:
-        //    Combines all individual `BTreeSet`s into one continuous stream of key strings.
         .flatten()
-        // 6. Collect all key strings into a single `BTreeSet`:
-        //    Ensures all keys are unique and maintains them in sorted order.
         .collect();
 
+    // 5. Merge: sort (in parallel) and remove keys found in more than one file.
+    all_file_keys.par_sort_unstable();
+    all_file_keys.dedup();
+
     Ok(all_file_keys)
 }
 
+/// Number of processed files that may wait in the channel for the sink.
+///
+/// Bounds memory use when the sink is slower than the extraction.
+const STREAM_CHANNEL_CAPACITY: usize = 64;
+
+/// Processes all EFD file entries in parallel, sending the keys of each file
+/// to `sink` as soon as the file is processed.
+///
+/// Extraction runs on the Rayon thread pool while the calling thread feeds the sink,
+/// so the output grows while the run is in progress. Files reach the sink in
+/// completion order. `sink.finish()` is called once all files were processed.
+///
+/// When a `cache` is given, unchanged files are loaded from it instead of parsed.
+///
+/// If the sink fails, the remaining extractions are cancelled and the sink error
+/// is returned; otherwise the first extraction error (if any) is returned.
+pub fn process_all_efd_files_streaming(
+    efd_entries: &[DirEntry],
+    config: &ExtractionConfig,
+    cache: Option<&KeyCache>,
+    sink: &mut dyn KeySink,
+) -> MyResult<()> {
+    send_efd_files_to_sink(efd_entries, config, cache, sink)?;
+    sink.finish()
+}
+
+/// Same as `process_all_efd_files_streaming`, but without calling `sink.finish()`,
+/// so the same sink can receive further batches of files (see `EfdWatcher`).
+pub fn send_efd_files_to_sink(
+    efd_entries: &[DirEntry],
+    config: &ExtractionConfig,
+    cache: Option<&KeyCache>,
+    sink: &mut dyn KeySink,
+) -> MyResult<()> {
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::sync_channel::<EfdFileKeys>(STREAM_CHANNEL_CAPACITY);
+
+        let producer = scope.spawn(move || {
+            efd_entries
+                .par_iter()
+                .try_for_each_with(sender, |sender, entry| {
+                    let file_keys = extract_efd_file_cached(entry, config, cache)?;
+                    // Fails only if the receiver was dropped after a sink error.
+                    sender.send(file_keys).map_err(|_| MyError::SinkClosed)
+                })
+        });
+
+        let sink_result = receiver
+            .iter()
+            .try_for_each(|file_keys| sink.write_file_keys(&file_keys));
+
+        // Unblock (and stop) the producers if the sink failed.
+        drop(receiver);
+
+        let producer_result = producer
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+
+        sink_result?;
+        producer_result
+    })
+}
+
+/// Writes the keys to `output_file`, one key per line.
+///
+/// If there are no keys, no file is created.
+pub fn write_keys_to_file<P>(chaves: &[Chave44], output_file: P) -> MyResult<()>
+where
+    P: AsRef<Path>,
+{
+    if chaves.is_empty() {
+        return Ok(());
+    }
+
+    let path = output_file.as_ref();
+    let file = File::create(path).map_err(|e| MyError::FileWriteError(path.to_path_buf(), e))?;
+    let mut writer = BufWriter::new(file);
+
+    for chave in chaves {
+        writeln!(writer, "{chave}")?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Extracts the unique 44-digit keys and the header of one EFD file,
+/// choosing the reading strategy.
+///
+/// Files larger than `config.mmap_chunk_size` are memory-mapped and scanned in
+/// parallel chunks (see `read_efd_file_mmap`); all other files are
+/// read line by line with `read_efd_file`.
+pub fn extract_efd_file(entry: &DirEntry, config: &ExtractionConfig) -> MyResult<EfdFileKeys> {
+    if let Some(chunk_size) = config.mmap_chunk_size {
+        let file_size = entry.metadata()?.len();
+
+        if file_size > chunk_size as u64 {
+            return read_efd_file_mmap(entry.path(), chunk_size, config);
+        }
+    }
+
+    read_efd_file_with(entry.path(), config)
+}
+
+/// Same as `extract_efd_file`, but reuses the result stored in `cache`
+/// when the file did not change since it was cached.
+pub fn extract_efd_file_cached(
+    entry: &DirEntry,
+    config: &ExtractionConfig,
+    cache: Option<&KeyCache>,
+) -> MyResult<EfdFileKeys> {
+    match cache {
+        Some(cache) => cache.get_or_extract(entry.path(), || extract_efd_file(entry, config)),
+        None => extract_efd_file(entry, config),
+    }
+}
+
 /// Processa uma única linha do arquivo, extraindo chaves ou sinalizando interrupção/ignorar.
 ///
 /// Retorna:
-/// - `Ok(Some(keys))`: Se chaves foram encontradas na linha.
+/// - `Ok(Some(line))`: Os campos da linha e as chaves encontradas.
 /// - `Ok(None)`: Se a linha deve ser ignorada (ex: poucos campos).
 /// - `Err(MyError::EofMarkerReached)`: Se "9999" foi encontrado (interrupção controlada).
 /// - `Err(MyError::...)`: Para outros erros reais (decodificação, etc.).
-fn process_line_for_keys(
-    line_bytes: Vec<u8>,
+pub(crate) fn process_line_for_keys(
+    line_bytes: &[u8],
     line_number: usize,
-    file_path: &PathBuf,
-) -> MyResult<Option<Vec<String>>> {
+    file_path: &Path,
+    config: &ExtractionConfig,
+) -> MyResult<Option<EfdLine>> {
     let trimmed_bytes = line_bytes.trim_ascii();
 
     // Decode bytes to String, propagating `EncodingError`
@@ -181,18 +385,49 @@ fn process_line_for_keys(
     }
 
     let mut keys_on_line = Vec::new();
+    let mut formatted_keys = Vec::new();
+    let mut url_keys = Vec::new();
 
     // If filters are passed, process fields to extract keys
-    for field_content in fields {
-        for capture in REGEX_CHAVE44.captures_iter(&field_content) {
+    for field_content in &fields {
+        for capture in REGEX_CHAVE44.captures_iter(field_content) {
             // The first capturing group (index 1) contains the actual 44-digit key.
-            if let Some(matched_key) = capture.get(1) {
-                keys_on_line.push(matched_key.as_str().to_string());
+            if let Some(chave) = capture
+                .get(1)
+                .and_then(|matched_key| Chave44::from_bytes(matched_key.as_str().as_bytes()))
+            {
+                keys_on_line.push(chave);
+            }
+        }
+
+        // Keys in NFC-e QR-code and SEFAZ query URLs, with the portal of the URL.
+        for url_key in extract_url_keys(field_content) {
+            keys_on_line.push(url_key.chave);
+            url_keys.push(url_key);
+        }
+
+        // Keys written in groups: "3525 0612 ..." or "3525.0612. ..."
+        if config.formatadas {
+            for capture in REGEX_CHAVE44_FORMATADA.captures_iter(field_content) {
+                if let Some(chave) = capture
+                    .get(1)
+                    .and_then(|matched_key| chave_formatada(matched_key.as_str()))
+                {
+                    keys_on_line.push(chave);
+                    formatted_keys.push(chave);
+                }
             }
         }
     }
 
-    Ok(Some(keys_on_line)) // Retorna as chaves encontradas nesta linha
+    // Retorna os campos e as chaves encontradas nesta linha
+    Ok(Some(EfdLine {
+        linha: line_number,
+        campos: fields,
+        chaves: keys_on_line,
+        formatadas: formatted_keys,
+        urls: url_keys,
+    }))
 }
 
 /// Processes a directory entry (file) to extract unique 44-digit keys.
@@ -207,17 +442,21 @@ fn process_line_for_keys(
 ///
 /// # Arguments
 /// * `entry` - A reference to a `DirEntry` representing the file to process.
+/// * `config` - The extraction options, shared by all the lines of the file.
 ///
 /// # Returns
-/// A `MyResult` containing a `BTreeSet<String>` of unique 44-digit keys
+/// A `MyResult` containing a sorted `Vec<Chave44>` of unique 44-digit keys
 /// found in the file. Returns `Err(MyError)` if file operations, decoding,
 /// or other unexpected issues occur.
-pub fn extract_keys_from_efd_file_funcional(entry: &DirEntry) -> MyResult<BTreeSet<String>> {
+pub fn extract_keys_from_efd_file_funcional(
+    entry: &DirEntry,
+    config: &ExtractionConfig,
+) -> MyResult<Vec<Chave44>> {
     let path = entry.path();
     let file = open_file(path)?; // Propaga qualquer erro ao abrir o arquivo
     let buffer = BufReader::new(file);
 
-    let mut collected_keys: BTreeSet<String> = BTreeSet::new();
+    let mut collected_keys: Vec<Chave44> = Vec::new();
 
     // `try_fold` é usado para iterar, acumular chaves e parar a iteração
     // se um erro (incluindo EofMarkerReached) for retornado pelo closure.
@@ -230,20 +469,16 @@ pub fn extract_keys_from_efd_file_funcional(entry: &DirEntry) -> MyResult<BTreeS
         .try_fold((), |_, (line_idx, line_bytes_result)| {
             let line_number = line_idx + 1; // Número da linha (1-based)
 
-            // Tenta processar a linha. O resultado é um MyResult<Option<Vec<String>>>
-            let keys_result: MyResult<Option<Vec<String>>> = match line_bytes_result {
-                Ok(line_bytes) => {
-                    process_line_for_keys(line_bytes, line_number, &path.to_path_buf())
-                }
+            // Tenta processar a linha. O resultado é um MyResult<Option<EfdLine>>
+            let keys_result: MyResult<Option<EfdLine>> = match line_bytes_result {
+                Ok(line_bytes) => process_line_for_keys(&line_bytes, line_number, path, config),
                 Err(e) => Err(e), // Erro de I/O da linha é propagado diretamente
             };
 
             // Gerencia o resultado do processamento da linha
             match keys_result {
-                Ok(Some(keys)) => {
-                    for key in keys {
-                        collected_keys.insert(key);
-                    }
+                Ok(Some(line)) => {
+                    collected_keys.extend(line.chaves);
                     Ok(()) // Continua a iteração (Ok para try_fold)
                 }
                 Ok(None) => Ok(()), // Linha ignorada, continua (Ok para try_fold)
@@ -254,9 +489,13 @@ pub fn extract_keys_from_efd_file_funcional(entry: &DirEntry) -> MyResult<BTreeS
     // FINAL RESULT HANDLING:
     // Differentiate between normal completion/controlled break and an actual error.
     match final_processing_status {
-        Ok(_) => Ok(collected_keys), // Iteração completou sem nenhum Err retornado pelo try_fold
-        Err(MyError::EofMarkerReached(..)) => Ok(collected_keys), // Interrompido por 9999 (sucesso)
-        Err(e) => Err(e),            // Interrompido por um erro real
+        // Iteração completou sem nenhum Err retornado pelo try_fold
+        // ou foi interrompida por 9999 (sucesso)
+        Ok(_) | Err(MyError::EofMarkerReached(..)) => {
+            sort_and_dedup(&mut collected_keys);
+            Ok(collected_keys)
+        }
+        Err(e) => Err(e), // Interrompido por um erro real
     }
 }
 
@@ -274,15 +513,26 @@ pub fn extract_keys_from_efd_file_funcional(entry: &DirEntry) -> MyResult<BTreeS
 /// * `entry` - A reference to a `DirEntry` representing the file to process.
 ///
 /// # Returns
-/// A `MyResult` containing a `BTreeSet<String>` of unique 44-digit keys
+/// A `MyResult` containing a sorted `Vec<Chave44>` of unique 44-digit keys
 /// found in the file. Returns `Err(MyError)` if file operations, decoding,
 /// or other unexpected issues occur.
-pub fn extract_keys_from_efd_file(entry: &DirEntry) -> MyResult<BTreeSet<String>> {
-    let path = entry.path(); // Get the file path from the directory entry
+pub fn extract_keys_from_efd_file(entry: &DirEntry) -> MyResult<Vec<Chave44>> {
+    read_efd_file(entry.path()).map(|file_keys| file_keys.chaves)
+}
+
+/// Reads an EFD file line by line, collecting its unique 44-digit keys and its header.
+///
+/// See `extract_keys_from_efd_file` for the processing rules.
+pub fn read_efd_file(path: &Path) -> MyResult<EfdFileKeys> {
+    read_efd_file_with(path, &ExtractionConfig::default())
+}
+
+/// Same as `read_efd_file`, with the key matching options of `config`.
+pub fn read_efd_file_with(path: &Path, config: &ExtractionConfig) -> MyResult<EfdFileKeys> {
     let file = open_file(path)?; // Open the file, propagating any I/O errors immediately
     let buffer = BufReader::new(file); // Create a buffered reader for efficient line-by-line processing
 
-    let mut collected_keys: BTreeSet<String> = BTreeSet::new(); // Initialize a set to store unique keys
+    let mut file_keys = EfdFileKeys::new(path); // Keys are deduplicated once the file is read
 
     // Iterate over each line of the file, splitting by the NEWLINE_BYTE.
     // `enumerate()` provides a 0-based index for each line.
@@ -296,13 +546,11 @@ pub fn extract_keys_from_efd_file(entry: &DirEntry) -> MyResult<BTreeSet<String>
         // Attempt to process the current line for 44-digit keys.
         // `process_line_for_keys` is responsible for decoding, splitting,
         // and identifying keys, as well as detecting the "9999" end-marker.
-        match process_line_for_keys(line_bytes, line_number, &path.to_path_buf()) {
-            Ok(Some(keys)) => {
-                // If the line was successfully processed and contained keys,
-                // insert each key into our `collected_keys` set.
-                for key in keys {
-                    collected_keys.insert(key);
-                }
+        match process_line_for_keys(&line_bytes, line_number, path, config) {
+            Ok(Some(line)) => {
+                // If the line was successfully processed, collect its keys
+                // (and the header, if the line is the "0000" record).
+                file_keys.add_line(line, &config.layout);
             }
             Ok(None) => {
                 // If the line was valid but should be ignored (e.g., too few fields),
@@ -312,8 +560,8 @@ pub fn extract_keys_from_efd_file(entry: &DirEntry) -> MyResult<BTreeSet<String>
             Err(MyError::EofMarkerReached(..)) => {
                 // The "9999" end-of-file marker was found.
                 // This is treated as a controlled, successful termination for the file.
-                // Return all keys collected up to this point.
-                return Ok(collected_keys);
+                // Stop reading: the keys collected up to this point are returned below.
+                break;
             }
             Err(e) => {
                 // Any other actual error (e.g., encoding issues) occurred during line processing.
@@ -323,10 +571,37 @@ pub fn extract_keys_from_efd_file(entry: &DirEntry) -> MyResult<BTreeSet<String>
         }
     }
 
-    // If the loop completes without encountering the "9999" marker or any errors,
-    // it means the entire file was processed to its end.
-    // Return all keys collected from the file.
-    Ok(collected_keys)
+    // Either the "9999" marker was found or the entire file was processed to its end.
+    // Sort and remove duplicates before returning the keys collected from the file.
+    Ok(file_keys.finish())
+}
+
+/// Calls `visit` with the (1-based) number and the fields of each line of an EFD file,
+/// up to the "9999" end-of-file marker.
+///
+/// Used by the reports that need the context of each line, rather than only the keys.
+pub fn for_each_efd_line<F>(path: &Path, mut visit: F) -> MyResult<()>
+where
+    F: FnMut(usize, &[String]) -> MyResult<()>,
+{
+    let file = open_file(path)?;
+    let buffer = BufReader::new(file);
+
+    for (line_idx, byte_result) in buffer.split(NEWLINE_BYTE).enumerate() {
+        let line_number = line_idx + 1;
+        let line_bytes: Vec<u8> = byte_result?;
+
+        let line_string = get_string_utf8(line_bytes.trim_ascii(), line_number, path)?;
+        let fields = split_line(line_string);
+
+        if fields.first().is_some_and(|f| f == "9999") {
+            break;
+        }
+
+        visit(line_number, &fields)?;
+    }
+
+    Ok(())
 }
 
 /// Converts a slice of bytes to a String, attempting UTF-8 first, then WINDOWS_1252.
@@ -428,6 +703,11 @@ mod lib_tests {
             .ok_or(MyError::TestDummyFileError)
     }
 
+    // Helper to parse the expected keys
+    fn parse_keys(texts: &[&str]) -> MyResult<Vec<Chave44>> {
+        texts.iter().map(|text| text.parse()).collect()
+    }
+
     /// cargo test -- --show-output basic
     #[test]
     fn test_extract_keys_from_efd_file_basic_extraction() -> MyResult<()> {
@@ -454,12 +734,12 @@ mod lib_tests {
 
         println!("result: {result:#?}");
 
-        let expected_keys: BTreeSet<String> = BTreeSet::from_iter([
-            "11111111111111111111111111111111111111111111".to_string(),
-            "12345678901234567890123456789012345678901234".to_string(),
-            "22222222222222222222222222222222222222222222".to_string(),
-            "33333333333333333333333333333333333333333333".to_string(),
-        ]);
+        let expected_keys: Vec<Chave44> = parse_keys(&[
+            "11111111111111111111111111111111111111111111",
+            "12345678901234567890123456789012345678901234",
+            "22222222222222222222222222222222222222222222",
+            "33333333333333333333333333333333333333333333",
+        ])?;
 
         assert_eq!(result, expected_keys);
         Ok(())
@@ -493,12 +773,10 @@ mod lib_tests {
 
         let result = extract_keys_from_efd_file(&entry)?;
 
-        let expected_keys: BTreeSet<String> = [
-            "11111111111111111111111111111111111111111111".to_string(),
-            "22222222222222222222222222222222222222222222".to_string(),
-        ]
-        .into_iter()
-        .collect();
+        let expected_keys: Vec<Chave44> = parse_keys(&[
+            "11111111111111111111111111111111111111111111",
+            "22222222222222222222222222222222222222222222",
+        ])?;
 
         assert_eq!(result, expected_keys);
         assert_eq!(result.len(), 2); // Ensure duplicates are removed
@@ -520,10 +798,8 @@ mod lib_tests {
 
         println!("result: {result:#?}");
 
-        let expected_keys: BTreeSet<String> =
-            ["11111111111111111111111111111111111111111111".to_string()]
-                .into_iter()
-                .collect();
+        let expected_keys: Vec<Chave44> =
+            parse_keys(&["11111111111111111111111111111111111111111111"])?;
 
         println!("expected_keys: {expected_keys:#?}");
 
@@ -559,7 +835,132 @@ mod lib_tests {
         "; // This is UTF-8
         let entry = create_dummy_direntry(&temp_dir, "PISCOFINS_UTF8.txt", file_content_utf8)?;
         let result = extract_keys_from_efd_file(&entry)?;
-        assert!(result.contains("11111111111111111111111111111111111111111111"));
+        assert!(result.contains(&"11111111111111111111111111111111111111111111".parse()?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_keys_with_alphanumeric_cnpj() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let file_content = r"
+|C100|0|1|PART01|55|00|1|123|352506AB3C5D7E000190550010000001231123456780|01062025|
+|C100|0|1|PART02|55|00|1|124|352506ab3c5d7e000190550010000001241123456789|01062025|
+        ";
+        let entry = create_dummy_direntry(&temp_dir, "PISCOFINS_ALFA.txt", file_content)?;
+
+        let result = extract_keys_from_efd_file(&entry)?;
+
+        let expected_keys: Vec<Chave44> = parse_keys(&[
+            "352506AB3C5D7E000190550010000001231123456780",
+            "352506AB3C5D7E000190550010000001241123456789",
+        ])?;
+
+        assert_eq!(result, expected_keys);
+        assert!(result.iter().all(|c| c.tipo() == TipoChave::Alfanumerica));
+        Ok(())
+    }
+
+    #[test]
+    fn test_formatted_keys_are_recognized_when_enabled() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let file_content = r"
+|C100|0|1|PART01|55|00|1|123|35250612345678000190550010000001231123456782|01062025|
+|0450|1|CHAVE 3525 0612 3456 7800 0190 5500 1000 0001 2311 2345 6782 (DANFE)|
+|0450|2|3525.06ab.3C5D.7E00.0190.5500.1000.0001.2311.2345.6784|
+|0450|3|3525-0612-3456-7800-0190-5500-1000-0001-2411-2345-6789|
+|0450|4|3525  0612 3456 7800 0190 5500 1000 0001 2511 2345 6789|
+        ";
+        let entry = create_dummy_direntry(&temp_dir, "PISCOFINS_FORMATADAS.txt", file_content)?;
+
+        // Disabled by default: only the contiguous key.
+        let file_keys = read_efd_file(entry.path())?;
+        assert_eq!(
+            file_keys.chaves,
+            parse_keys(&["35250612345678000190550010000001231123456782"])?
+        );
+
+        let config = ExtractionConfig {
+            formatadas: true,
+            ..Default::default()
+        };
+        let file_keys = read_efd_file_with(entry.path(), &config)?;
+
+        // The last line has a double space and is not recognized.
+        assert_eq!(
+            file_keys.chaves,
+            parse_keys(&[
+                "35250612345678000190550010000001231123456782",
+                "35250612345678000190550010000001241123456789",
+                "352506AB3C5D7E000190550010000001231123456784",
+            ])?
+        );
+        // The first key was also found formatted, in the second line.
+        assert_eq!(
+            file_keys.formatadas,
+            parse_keys(&[
+                "35250612345678000190550010000001231123456782",
+                "35250612345678000190550010000001241123456789",
+                "352506AB3C5D7E000190550010000001231123456784",
+            ])?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_all_efd_files_streaming_sends_each_file() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        for (i, key) in ["1", "2", "3", "1"].iter().enumerate() {
+            create_dummy_direntry(
+                &temp_dir,
+                &format!("PISCOFINS_{i}.txt"),
+                &format!("|C100|{}|\n", key.repeat(44)),
+            )?;
+        }
+
+        let entries: Vec<DirEntry> = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .flatten()
+            .filter(|entry| is_efd_file(entry, &EFD_CONTRIBUICOES))
+            .collect();
+
+        let mut text = TextSink::new(Vec::new());
+        process_all_efd_files_streaming(&entries, &ExtractionConfig::default(), None, &mut text)?;
+        let written = String::from_utf8_lossy(&text.into_inner()).to_string();
+
+        // Without deduplication, every file is written: 4 lines.
+        assert_eq!(written.lines().count(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_all_efd_files_parallel_merges_unique_keys() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        create_dummy_direntry(
+            &temp_dir,
+            "PISCOFINS_A.txt",
+            "|C100|33333333333333333333333333333333333333333333|\n|C100|11111111111111111111111111111111111111111111|\n",
+        )?;
+        create_dummy_direntry(
+            &temp_dir,
+            "PISCOFINS_B.txt",
+            "|C100|22222222222222222222222222222222222222222222|\n|C100|11111111111111111111111111111111111111111111|\n",
+        )?;
+
+        let entries: Vec<DirEntry> = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .flatten()
+            .filter(|entry| is_efd_file(entry, &EFD_CONTRIBUICOES))
+            .collect();
+
+        let result = process_all_efd_files_parallel(&entries, &ExtractionConfig::default())?;
+
+        let expected_keys: Vec<Chave44> = parse_keys(&[
+            "11111111111111111111111111111111111111111111",
+            "22222222222222222222222222222222222222222222",
+            "33333333333333333333333333333333333333333333",
+        ])?;
+
+        assert_eq!(result, expected_keys);
         Ok(())
     }
 }
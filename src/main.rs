@@ -1,8 +1,18 @@
-use claudiofsr_lib::BTreeSetExtension;
-use std::{collections::BTreeSet, process, time::Instant};
+use std::{
+    process,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
 use extrair_chaves_de_44_digitos::{
-    get_efd_entries, process_all_efd_files_parallel, Arguments, MyResult,
+    comparar, create_sink, diagnose_efd_files, get_efd_entries, read_event_dir, read_keys_input,
+    read_lista, read_xml_dir, send_efd_files_to_sink, with_dedup, write_conciliacao,
+    write_diagnostico, write_diff, write_estatisticas, Arguments, CanceladasReportSink,
+    CicloVidaSink, Comando, ConciliacaoSink, ConferenciaReportSink, ContingenciaReportSink,
+    DiffArgs, EfdFileKeys, EfdWatcher, EstatisticasSink, ExtractionConfig, FilterSink, KeyCache,
+    KeyFilter, KeySink, LayoutDefinition, MyError, MyResult, NumeracaoReportSink,
+    ParticipanteReportSink, Pendencia, Presenca, ReconcileArgs, StatsArgs,
 };
 
 /*
@@ -31,6 +41,176 @@ fn main() {
     }
 }
 
+/// Prints the number of keys of each processed file and,
+/// at the end, the totals of the unique keys of the run.
+#[derive(Default)]
+struct VerboseSink {
+    estatisticas: EstatisticasSink,
+}
+
+impl KeySink for VerboseSink {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        let chaves = &file_keys.chaves;
+        if !chaves.is_empty() {
+            println!("{}: {} chaves", file_keys.path.display(), chaves.len());
+        }
+        self.estatisticas.write_file_keys(file_keys)
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        println!(
+            "estatisticas:\n{}",
+            self.estatisticas.estatisticas(10).summary()
+        );
+        Ok(())
+    }
+}
+
+/// Warns about files whose layout version (COD_VER) is unknown to the layout:
+/// their keys were found by scanning the whole lines, without the field map.
+struct VersaoLayoutSink {
+    layout: Arc<LayoutDefinition>,
+}
+
+impl KeySink for VersaoLayoutSink {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        if let Some(cod_ver) = file_keys.versao_desconhecida(&self.layout) {
+            eprintln!(
+                "aviso: {}: versão {cod_ver} do leiaute desconhecida em {}; chaves extraídas da linha inteira",
+                file_keys.path.display(),
+                self.layout.nome
+            );
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        Ok(())
+    }
+}
+
+/// Polls the EFD files every `--watch_interval` seconds and sends the
+/// new or modified ones to the sinks (and the cache).
+fn watch(
+    arguments: &Arguments,
+    config: &ExtractionConfig,
+    cache: Option<&KeyCache>,
+    sinks: &mut dyn KeySink,
+    processed_entries: &[walkdir::DirEntry],
+) -> MyResult<()> {
+    let interval = Duration::from_secs(arguments.watch_interval.max(1));
+    let mut watcher = EfdWatcher::new(processed_entries)?;
+
+    eprintln!(
+        "Watching for new EFD files every {} s (Ctrl+C to stop)...",
+        interval.as_secs()
+    );
+
+    loop {
+        thread::sleep(interval);
+
+        let ready = watcher.poll(get_efd_entries(arguments, config)?)?;
+        if ready.is_empty() {
+            continue;
+        }
+
+        send_efd_files_to_sink(&ready, config, cache, sinks)?;
+
+        if arguments.verbose {
+            println!("watch: {} arquivos novos ou modificados", ready.len());
+        }
+    }
+}
+
+/// Compares the keys of two inputs and writes the differences.
+fn diff(arguments: &Arguments, diff_args: &DiffArgs) -> MyResult<()> {
+    let config = ExtractionConfig::build(arguments)?;
+    let a = read_keys_input(&diff_args.a, &config)?;
+    let b = read_keys_input(&diff_args.b, &config)?;
+
+    let diferencas = comparar(&a, &b, &diff_args.por);
+    write_diff(&diferencas, &diff_args.output)?;
+
+    let contar = |presenca| diferencas.iter().filter(|d| d.presenca == presenca).count();
+    println!(
+        "somente em A: {}, somente em B: {}, em ambos: {} ({})",
+        contar(Presenca::SomenteA),
+        contar(Presenca::SomenteB),
+        contar(Presenca::Ambos),
+        diff_args.output.display()
+    );
+    Ok(())
+}
+
+/// Reconciles the keys of the EFD files with the keys of the XML documents
+/// or of an official list of documents.
+fn reconcile(arguments: &Arguments, reconcile_args: &ReconcileArgs) -> MyResult<()> {
+    let config = ExtractionConfig::build(arguments)?;
+    let efd_entries = get_efd_entries(arguments, &config)?;
+
+    let mut sink = FilterSink::new(ConciliacaoSink::new(), arguments.key_filter());
+    send_efd_files_to_sink(&efd_entries, &config, None, &mut sink)?;
+
+    if arguments.key_filter() != KeyFilter::default() {
+        println!("filtros:\n{}", sink.stats().summary());
+    }
+
+    let (fonte, mut externas) = match (&reconcile_args.xml, &reconcile_args.contra) {
+        (Some(xml), _) => ("XML", read_xml_dir(xml)?),
+        (None, Some(contra)) => (
+            "lista",
+            read_lista(contra, &reconcile_args.opcoes_lista()?)?,
+        ),
+        (None, None) => {
+            return Err(MyError::InvalidOptions(
+                "reconcile requires --xml or --contra".to_string(),
+            ))
+        }
+    };
+    // The document filters (operation, situation, issue) only apply to the EFD keys.
+    let filtro = arguments.key_filter();
+    externas.retain(|externa| filtro.accepts_chave(&externa.chave));
+
+    let pendencias = sink.into_inner().conciliar(&externas);
+    write_conciliacao(&pendencias, &reconcile_args.output)?;
+
+    let contar = |pendencia| {
+        pendencias
+            .iter()
+            .filter(|p| p.pendencia == pendencia)
+            .count()
+    };
+    println!(
+        "{} chaves em {fonte}, ausentes na EFD: {}, ausentes em {fonte}: {} ({})",
+        externas.len(),
+        contar(Pendencia::AusenteNaEfd),
+        contar(Pendencia::AusenteNaFonte),
+        reconcile_args.output.display()
+    );
+    Ok(())
+}
+
+/// Prints (and writes) the totals of the unique keys of the EFD files.
+fn stats(arguments: &Arguments, stats_args: &StatsArgs) -> MyResult<()> {
+    let config = ExtractionConfig::build(arguments)?;
+    let efd_entries = get_efd_entries(arguments, &config)?;
+
+    let mut sink = FilterSink::new(EstatisticasSink::new(), arguments.key_filter());
+    send_efd_files_to_sink(&efd_entries, &config, None, &mut sink)?;
+
+    if arguments.key_filter() != KeyFilter::default() {
+        println!("filtros:\n{}", sink.stats().summary());
+    }
+
+    let estatisticas = sink.into_inner().estatisticas(stats_args.top);
+    println!("{}", estatisticas.summary());
+
+    if let Some(output) = &stats_args.output {
+        write_estatisticas(&estatisticas, output)?;
+    }
+    Ok(())
+}
+
 /// Contains the core logic of the application.
 /// It parses arguments, finds files, processes them in parallel,
 /// writes the results, and handles verbose output/timing.
@@ -40,20 +220,144 @@ fn main() {
 fn run() -> MyResult<()> {
     let time = Instant::now(); // Record start time for performance measurement
     let arguments = Arguments::build()?; // Parse command-line arguments, propagating errors
-    let efd_entries = get_efd_entries(&arguments)?; // Get a list of EFD files, propagating errors
 
-    // Process all EFD files in parallel to extract unique 44-digit keys.
-    // This leverages Rayon for efficiency and collects results into a single BTreeSet.
-    let chaves: BTreeSet<String> = process_all_efd_files_parallel(&efd_entries)?;
+    match &arguments.comando {
+        Some(Comando::Diff(diff_args)) => return diff(&arguments, diff_args),
+        Some(Comando::Reconcile(reconcile_args)) => return reconcile(&arguments, reconcile_args),
+        Some(Comando::Stats(stats_args)) => return stats(&arguments, stats_args),
+        None => {}
+    }
+
+    let config = ExtractionConfig::build(&arguments)?; // Options for reading each EFD file
+    let efd_entries = get_efd_entries(&arguments, &config)?; // Get a list of EFD files, propagating errors
+
+    let output_filename = arguments.output_path(); // Define the output file name
+
+    // The output file receives the unique keys of the chosen scope, in the chosen format.
+    let output_sink = create_sink(arguments.formato, &output_filename)?;
+    let output_sink = with_dedup(
+        arguments.dedup,
+        arguments.escopo,
+        arguments.contar,
+        output_sink,
+    )?;
+    let mut sinks: Vec<Box<dyn KeySink>> = vec![output_sink];
+
+    // Keys by participant name, from every file (before deduplication).
+    if let Some(path) = &arguments.por_participante {
+        sinks.push(Box::new(ParticipanteReportSink::create(path)?));
+    }
+
+    // Periods where each key was declared, from every file (before deduplication).
+    if let Some(path) = &arguments.ciclo_de_vida {
+        sinks.push(Box::new(CicloVidaSink::create(
+            path,
+            arguments.duplicados_entre_periodos,
+        )?));
+    }
+
+    // Keys issued in contingency, whose later authorization must be confirmed.
+    if let Some(path) = &arguments.contingencia {
+        sinks.push(Box::new(ContingenciaReportSink::create(path)?));
+    }
+
+    // Records whose key disagrees with the other fields, written as each file is processed.
+    if let Some(path) = &arguments.conferencia {
+        sinks.push(Box::new(ConferenciaReportSink::create(path)?));
+    }
+
+    // Gaps and duplicates in the numbering of the documents issued by the company.
+    if let Some(path) = &arguments.numeracao {
+        sinks.push(Box::new(NumeracaoReportSink::create(path)?));
+    }
+
+    // Cancelled documents declared as regular.
+    if let (Some(eventos), Some(path)) = (&arguments.eventos, &arguments.canceladas) {
+        let eventos = read_event_dir(eventos)?;
+        if arguments.verbose {
+            let canceladas = eventos
+                .iter()
+                .filter(|evento| evento.cancelamento())
+                .count();
+            println!(
+                "eventos: {} eventos, {canceladas} cancelamentos",
+                eventos.len()
+            );
+        }
+        sinks.push(Box::new(CanceladasReportSink::create(path, eventos)?));
+    }
+
+    // Files of unknown layout versions are read without the field map.
+    sinks.push(Box::new(VersaoLayoutSink {
+        layout: Arc::clone(&config.layout),
+    }));
+
+    // Print the keys of each file if verbose mode is enabled.
+    if arguments.verbose {
+        sinks.push(Box::new(VerboseSink::default()));
+    }
+
+    // Only the requested classes of 44-digit sequences (and documents) reach the outputs.
+    let mut sinks = FilterSink::new(sinks, arguments.key_filter());
+
+    // Reuse the results of files already processed in previous runs.
+    let cache: Option<KeyCache> = if arguments.no_cache {
+        None
+    } else if arguments.rebuild_cache {
+        Some(KeyCache::empty(
+            &arguments.cache,
+            &config.cache_signature()?,
+        ))
+    } else {
+        Some(KeyCache::open(&arguments.cache, &config.cache_signature()?))
+    };
 
-    let output_filename = "efd-chaves_eletronicas.txt"; // Define the output file name
+    // Process all EFD files in parallel to extract 44-digit keys.
+    // This leverages Rayon for efficiency and writes the keys of each file as soon as it is processed.
+    send_efd_files_to_sink(&efd_entries, &config, cache.as_ref(), &mut sinks)?;
+
+    if let Some(cache) = &cache {
+        cache.prune()?;
+
+        if arguments.verbose {
+            println!(
+                "cache: {} arquivos reaproveitados, {} arquivos processados",
+                cache.hits(),
+                cache.misses()
+            );
+        }
+    }
+
+    // Report the sequences that are probably mistyped keys.
+    if let Some(diagnostico) = &arguments.diagnostico {
+        let quase_chaves = diagnose_efd_files(&efd_entries)?;
+        write_diagnostico(&quase_chaves, diagnostico)?;
+
+        if arguments.verbose {
+            println!(
+                "diagnostico: {} quase-chaves em {}",
+                quase_chaves.len(),
+                diagnostico.display()
+            );
+        }
+    }
+
+    // Keep extracting keys from new or modified files: this only returns on error.
+    if arguments.watch {
+        return watch(
+            &arguments,
+            &config,
+            cache.as_ref(),
+            &mut sinks,
+            &efd_entries,
+        );
+    }
 
-    // Write the collected keys to the specified file.
-    chaves.write_to_file(output_filename)?;
+    sinks.finish()?;
 
-    // Print collected keys if verbose mode is enabled.
-    if arguments.verbose && !chaves.is_empty() {
-        println!("{} chaves: {chaves:#?}", chaves.len());
+    // The keys discarded by the filters are always reported when a filter is set.
+    if arguments.verbose || arguments.key_filter() != KeyFilter::default() {
+        println!("filtros:\n{}", sinks.stats().summary());
     }
 
     // Print total execution time if time tracking is enabled.
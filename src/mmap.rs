@@ -0,0 +1,262 @@
+use crate::{
+    error::{MyError, MyResult},
+    get_string_utf8, process_line_for_keys, split_line, EfdFileKeys, EfdHeader, ExtractionConfig,
+    NEWLINE_BYTE,
+};
+use memmap2::Mmap;
+use rayon::prelude::*;
+use std::{fs::File, path::Path};
+
+/// Keys found in one chunk of a memory-mapped file.
+struct ChunkKeys {
+    file_keys: EfdFileKeys,
+    /// `true` if the chunk contains the "9999" end-of-file marker.
+    eof_marker_reached: bool,
+}
+
+/// Memory-maps a file for reading.
+///
+/// # Safety
+///
+/// The mapping is only valid while no other process truncates or modifies the
+/// file. EFD files are read-only inputs for this program, which is the same
+/// assumption made by the buffered reader path.
+#[allow(unsafe_code)]
+fn map_file(path: &Path) -> MyResult<Mmap> {
+    let file = File::open(path).map_err(|e| MyError::FileReadError(path.to_path_buf(), e))?;
+    // SAFETY: see the function documentation.
+    let mmap =
+        unsafe { Mmap::map(&file) }.map_err(|e| MyError::FileReadError(path.to_path_buf(), e))?;
+    Ok(mmap)
+}
+
+/// Splits `bytes` into chunks of about `chunk_size` bytes.
+///
+/// Every chunk, except possibly the last one, ends right after a newline byte,
+/// so no line is shared between two chunks.
+pub(crate) fn split_at_newlines(bytes: &[u8], chunk_size: usize) -> Vec<&[u8]> {
+    let chunk_size = chunk_size.max(1);
+    let mut chunks = Vec::new();
+    let mut rest = bytes;
+
+    while !rest.is_empty() {
+        let end = if rest.len() <= chunk_size {
+            rest.len()
+        } else {
+            // Extend the chunk up to (and including) the next newline.
+            rest[chunk_size..]
+                .iter()
+                .position(|&byte| byte == NEWLINE_BYTE)
+                .map_or(rest.len(), |pos| chunk_size + pos + 1)
+        };
+
+        let (chunk, remaining) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = remaining;
+    }
+
+    chunks
+}
+
+/// Returns the (1-based) number of the first line of each chunk.
+///
+/// Newlines are counted in parallel, followed by a prefix sum.
+pub(crate) fn first_line_numbers(chunks: &[&[u8]]) -> Vec<usize> {
+    let newlines: Vec<usize> = chunks
+        .par_iter()
+        .map(|chunk| chunk.iter().filter(|&&byte| byte == NEWLINE_BYTE).count())
+        .collect();
+
+    newlines
+        .iter()
+        .scan(1, |line_number, count| {
+            let first_line = *line_number;
+            *line_number += count;
+            Some(first_line)
+        })
+        .collect()
+}
+
+/// Finds the header as `read_efd_file_with` does: the first "0000" record before
+/// the "9999" end-of-file marker, after any blank lines or lines of other records.
+fn find_header(bytes: &[u8], path: &Path, config: &ExtractionConfig) -> Option<EfdHeader> {
+    for (line_idx, line_bytes) in bytes.split(|&byte| byte == NEWLINE_BYTE).enumerate() {
+        let Ok(line) = get_string_utf8(line_bytes.trim_ascii(), line_idx + 1, path) else {
+            continue;
+        };
+        let campos = split_line(line);
+
+        if campos.first().is_some_and(|campo| campo == "9999") {
+            break;
+        }
+        if let Some(header) = EfdHeader::from_campos(&campos, &config.layout) {
+            return Some(header);
+        }
+    }
+    None
+}
+
+/// Scans the lines of one chunk, stopping at the "9999" end-of-file marker.
+fn extract_keys_from_chunk(
+    chunk: &[u8],
+    first_line: usize,
+    path: &Path,
+    header: Option<&EfdHeader>,
+    config: &ExtractionConfig,
+) -> MyResult<ChunkKeys> {
+    // Every chunk is read with the layout version of the file.
+    let mut file_keys = EfdFileKeys {
+        header: header.cloned(),
+        ..EfdFileKeys::new(path)
+    };
+
+    for (line_idx, line_bytes) in chunk.split(|&byte| byte == NEWLINE_BYTE).enumerate() {
+        match process_line_for_keys(line_bytes, first_line + line_idx, path, config) {
+            Ok(Some(line)) => file_keys.add_line(line, &config.layout),
+            Ok(None) => continue,
+            Err(MyError::EofMarkerReached(..)) => {
+                return Ok(ChunkKeys {
+                    file_keys,
+                    eof_marker_reached: true,
+                })
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(ChunkKeys {
+        file_keys,
+        eof_marker_reached: false,
+    })
+}
+
+/// Extracts unique 44-digit keys (and the header) from a (large) file by
+/// memory-mapping it and scanning chunks of about `chunk_size` bytes in parallel with Rayon.
+///
+/// The result is the same as `read_efd_file_with`:
+/// - Chunks after the one containing the "9999" marker are discarded, and so are
+///   their errors, since the sequential reader would never have read them.
+/// - Line numbers reported in errors refer to the whole file.
+pub fn read_efd_file_mmap(
+    path: &Path,
+    chunk_size: usize,
+    config: &ExtractionConfig,
+) -> MyResult<EfdFileKeys> {
+    let mmap = map_file(path)?;
+    let chunks = split_at_newlines(&mmap, chunk_size);
+    let first_lines = first_line_numbers(&chunks);
+
+    // The 0000 record has the layout version of the file.
+    let header = find_header(&mmap, path, config);
+
+    let chunk_results: Vec<MyResult<ChunkKeys>> = chunks
+        .par_iter()
+        .zip(first_lines)
+        .map(|(chunk, first_line)| {
+            extract_keys_from_chunk(chunk, first_line, path, header.as_ref(), config)
+        })
+        .collect();
+
+    let mut file_keys = EfdFileKeys::new(path);
+
+    // Results are visited in file order, as the sequential reader would.
+    for chunk_result in chunk_results {
+        let chunk_keys = chunk_result?;
+        file_keys.append(chunk_keys.file_keys);
+
+        if chunk_keys.eof_marker_reached {
+            break;
+        }
+    }
+
+    Ok(file_keys.finish())
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output mmap_tests
+#[cfg(test)]
+mod mmap_tests {
+    use super::*;
+    use crate::{sort_and_dedup, Chave44};
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn chunks_end_at_newlines() {
+        let bytes = b"|A|1|\n|B|22|\n|C|333|\n|D|4444|";
+        let chunks = split_at_newlines(bytes, 4);
+
+        assert_eq!(chunks.concat(), bytes);
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|chunk| chunk.ends_with(b"\n")));
+        assert_eq!(first_line_numbers(&chunks), [1, 2, 3, 4]);
+
+        let chunks = split_at_newlines(bytes, 12);
+        assert_eq!(chunks, [&b"|A|1|\n|B|22|\n"[..], b"|C|333|\n|D|4444|"]);
+        assert_eq!(first_line_numbers(&chunks), [1, 3]);
+    }
+
+    #[test]
+    fn mmap_extraction_matches_buffered_extraction() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("PISCOFINS_MMAP.txt");
+
+        let mut content =
+            String::from("|0000|006|0|||01062025|30062025|EMPRESA|12345678000190|SP|\n");
+        for i in 0..200 {
+            content.push_str(&format!("|C100|0|1|{i}|{:044}|\n", i % 150));
+        }
+        content.push_str("|9999|201|\n");
+        content.push_str(&format!("|C100|{}|\n", "7".repeat(44)));
+        fs::write(&path, &content)?;
+
+        let file_keys = read_efd_file_mmap(&path, 256, &ExtractionConfig::default())?;
+
+        let mut expected: Vec<Chave44> = (0..150)
+            .map(|i| format!("{i:044}").parse())
+            .collect::<MyResult<_>>()?;
+        sort_and_dedup(&mut expected);
+
+        assert_eq!(file_keys, crate::read_efd_file(&path)?);
+        assert_eq!(file_keys.chaves, expected);
+        assert_eq!(
+            file_keys.header.map(|header| header.cnpj),
+            Some("12345678000190".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn header_after_a_bom_or_blank_lines_is_found_by_both_readers() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("PISCOFINS_BOM.txt");
+
+        let mut content = String::from(
+            "\u{FEFF}\n\n|0000|005|0|||01062025|30062025|EMPRESA|12345678000190|SP|\n",
+        );
+        for i in 0..100 {
+            content.push_str(&format!(
+                "|C500|F1|66|00|1||{i}|15062025|15062025|0|0||0|0|{i:044}|\n"
+            ));
+        }
+        fs::write(&path, &content)?;
+
+        let buffered = crate::read_efd_file(&path)?;
+        let mapped = read_efd_file_mmap(&path, 256, &ExtractionConfig::default())?;
+
+        assert_eq!(mapped, buffered);
+        assert_eq!(
+            mapped.header.map(|header| header.cod_ver),
+            Some("005".to_string())
+        );
+        // Version 005 has no key field in C500: the chunks used the version of the header.
+        assert!(mapped.documentos.is_empty());
+        assert_eq!(mapped.chaves.len(), 100);
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{
+    error::{MyError, MyResult},
+    Chave44, EfdFileKeys, Emissao, KeySink,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// Kind of break in the numbering of a series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Falha {
+    /// Numbers missing between two numbers found.
+    Lacuna,
+    /// A number found in two or more keys.
+    Duplicado,
+}
+
+/// A gap or a duplicate in the document numbers of one issuer CNPJ, model and series,
+/// within one month of issue.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct FalhaNumeracao {
+    pub cnpj: String,
+    pub modelo: u8,
+    pub serie: u16,
+    /// Month of issue (`aaaa-mm`, from the AAMM of the keys).
+    pub periodo: String,
+    pub falha: Falha,
+    /// First and last missing numbers (gaps) or the repeated number (duplicates).
+    pub numero_inicial: u32,
+    pub numero_final: u32,
+    /// Missing numbers (gaps) or keys with the repeated number (duplicates).
+    pub quantidade: u32,
+    /// Keys with the repeated number, separated by spaces.
+    pub chaves: String,
+}
+
+/// Issuer CNPJ, model, series and month of issue of a sequence of numbers.
+type Sequencia = (String, u8, u16, String);
+
+/// Finds the gaps and duplicates in the numbering of `chaves`, grouped by issuer CNPJ,
+/// model, series and month of issue, sorted by group and number.
+///
+/// Only the numbers between the first and the last number found in each group are checked.
+pub fn falhas_de_numeracao<'a>(
+    chaves: impl IntoIterator<Item = &'a Chave44>,
+) -> Vec<FalhaNumeracao> {
+    let mut grupos: BTreeMap<Sequencia, BTreeMap<u32, BTreeSet<Chave44>>> = BTreeMap::new();
+
+    for &chave in chaves {
+        let aamm = chave.aamm();
+        let periodo = format!("20{}-{}", &aamm[..2], &aamm[2..]);
+        let grupo = (chave.cnpj(), chave.modelo(), chave.serie(), periodo);
+        grupos
+            .entry(grupo)
+            .or_default()
+            .entry(chave.numero())
+            .or_default()
+            .insert(chave);
+    }
+
+    let mut falhas = Vec::new();
+
+    for ((cnpj, modelo, serie, periodo), numeros) in grupos {
+        let falha = |falha, numero_inicial, numero_final, quantidade, chaves| FalhaNumeracao {
+            cnpj: cnpj.clone(),
+            modelo,
+            serie,
+            periodo: periodo.clone(),
+            falha,
+            numero_inicial,
+            numero_final,
+            quantidade,
+            chaves,
+        };
+
+        let mut anterior: Option<u32> = None;
+
+        for (&numero, chaves) in &numeros {
+            if let Some(anterior) = anterior.filter(|&anterior| numero > anterior + 1) {
+                falhas.push(falha(
+                    Falha::Lacuna,
+                    anterior + 1,
+                    numero - 1,
+                    numero - anterior - 1,
+                    String::new(),
+                ));
+            }
+
+            if chaves.len() > 1 {
+                let texto: Vec<String> = chaves.iter().map(Chave44::to_string).collect();
+                falhas.push(falha(
+                    Falha::Duplicado,
+                    numero,
+                    numero,
+                    chaves.len() as u32,
+                    texto.join(" "),
+                ));
+            }
+
+            anterior = Some(numero);
+        }
+    }
+
+    falhas
+}
+
+/// Collects the access keys issued by the declaring company (see `EfdFileKeys::emissao`)
+/// and writes the gaps and duplicates of their numbering as CSV.
+///
+/// Numbers missing from the outbound documents of a series are a classic sign of
+/// undeclared sales.
+pub struct NumeracaoReportSink<W: Write> {
+    writer: W,
+    chaves: BTreeSet<Chave44>,
+}
+
+impl<W: Write> NumeracaoReportSink<W> {
+    pub fn new(writer: W) -> Self {
+        NumeracaoReportSink {
+            writer,
+            chaves: BTreeSet::new(),
+        }
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl NumeracaoReportSink<BufWriter<File>> {
+    /// Creates the report file.
+    pub fn create(path: &Path) -> MyResult<Self> {
+        let file =
+            File::create(path).map_err(|e| MyError::FileWriteError(path.to_path_buf(), e))?;
+        Ok(NumeracaoReportSink::new(BufWriter::new(file)))
+    }
+}
+
+impl<W: Write> KeySink for NumeracaoReportSink<W> {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        let proprias = file_keys
+            .chaves
+            .iter()
+            .filter(|chave| file_keys.emissao(chave) == Some(Emissao::Propria));
+        self.chaves.extend(proprias);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        let mut writer = csv::Writer::from_writer(&mut self.writer);
+        for falha in falhas_de_numeracao(&std::mem::take(&mut self.chaves)) {
+            writer.serialize(falha)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output numeracao_tests
+#[cfg(test)]
+mod numeracao_tests {
+    use super::*;
+    use crate::read_efd_file;
+    use crate::test_utils::chave_com_dv;
+    use std::fs;
+    use tempfile::tempdir;
+
+    /// NF-e of series 1 of the CNPJ 12345678000190, with a valid check digit.
+    fn nfe(aamm: &str, numero: u32, c_nf: u32) -> MyResult<Chave44> {
+        chave_com_dv(&format!("35{aamm}1234567800019055001{numero:09}1{c_nf:08}"))
+    }
+
+    #[test]
+    fn gaps_and_duplicates_of_self_issued_keys() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("PISCOFINS_SAIDAS.txt");
+
+        let chaves = [
+            nfe("2506", 10, 1)?,
+            nfe("2506", 11, 2)?,
+            nfe("2506", 11, 3)?,
+            nfe("2506", 15, 4)?,
+            // Another month: a new sequence.
+            nfe("2507", 20, 5)?,
+        ];
+        // Third-party key: not checked.
+        let terceiro = "352506AB3C5D7E000190550010000001231123456784";
+
+        let linhas: String = chaves
+            .iter()
+            .map(|chave| format!("|C100|1|0|C1|55|00|1|1|{chave}|15062025|\n"))
+            .collect();
+        fs::write(
+            &path,
+            format!(
+                "|0000|006|0|||01062025|30062025|EMPRESA|12345678000271|SP|\n{linhas}\
+                 |C100|0|1|F1|55|00|1|123|{terceiro}|15062025|\n"
+            ),
+        )?;
+
+        let mut report = NumeracaoReportSink::new(Vec::new());
+        report.write_file_keys(&read_efd_file(&path)?)?;
+        report.finish()?;
+
+        let csv = String::from_utf8(report.into_inner()).unwrap_or_default();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            [
+                "cnpj,modelo,serie,periodo,falha,numero_inicial,numero_final,quantidade,chaves",
+                &format!(
+                    "12345678000190,55,1,2025-06,duplicado,11,11,2,{} {}",
+                    chaves[1], chaves[2]
+                ),
+                "12345678000190,55,1,2025-06,lacuna,12,14,3,",
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+use crate::{sigla_uf, Chave44, Registro};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Participant of the "0150" record (suppliers, customers, carriers...).
+///
+/// EFD Contribuições layout:
+///
+/// `|0150|COD_PART|NOME|COD_PAIS|CNPJ|CPF|IE|COD_MUN|SUFRAMA|END|NUM|COMPL|BAIRRO|`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Participante {
+    /// Participant code (COD_PART), referenced by the documents.
+    pub cod_part: String,
+    /// Name (NOME).
+    pub nome: String,
+    /// CNPJ (CNPJ), empty for individuals.
+    pub cnpj: String,
+    /// CPF (CPF), empty for companies.
+    pub cpf: String,
+    /// IBGE municipality code (COD_MUN); its first two digits are the UF code.
+    pub cod_mun: String,
+}
+
+impl Participante {
+    /// Builds the participant from a "0150" record (see `LayoutDefinition`).
+    ///
+    /// Returns `None` for any other record.
+    pub fn from_registro(registro: &Registro) -> Option<Self> {
+        if registro.nome() != "0150" {
+            return None;
+        }
+
+        Some(Participante {
+            cod_part: registro.texto("COD_PART"),
+            nome: registro.texto("NOME"),
+            cnpj: registro.texto("CNPJ"),
+            cpf: registro.texto("CPF"),
+            cod_mun: registro.texto("COD_MUN"),
+        })
+    }
+
+    /// State of the participant, from the municipality code.
+    pub fn uf(&self) -> Option<&'static str> {
+        self.cod_mun.get(..2)?.parse().ok().and_then(sigla_uf)
+    }
+
+    /// CNPJ, or CPF for individuals.
+    pub fn cnpj_ou_cpf(&self) -> Option<String> {
+        [&self.cnpj, &self.cpf]
+            .into_iter()
+            .find(|doc| !doc.is_empty())
+            .cloned()
+    }
+
+    /// The issuer field of the keys of documents issued by this participant:
+    /// the CNPJ, or "000" followed by the CPF.
+    pub fn cnpj_da_chave(&self) -> Option<String> {
+        if !self.cnpj.is_empty() {
+            Some(self.cnpj.to_uppercase())
+        } else if !self.cpf.is_empty() {
+            Some(format!("000{}", self.cpf))
+        } else {
+            None
+        }
+    }
+}
+
+/// Type of operation of a document (IND_OPER).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operacao {
+    /// "0": purchases and other entries.
+    Entrada,
+    /// "1": sales and other exits.
+    Saida,
+}
+
+impl Operacao {
+    /// Parses the IND_OPER field.
+    pub fn from_ind_oper(ind_oper: &str) -> Option<Self> {
+        match ind_oper {
+            "0" => Some(Operacao::Entrada),
+            "1" => Some(Operacao::Saida),
+            _ => None,
+        }
+    }
+}
+
+/// Situation of a document (COD_SIT, table 4.1.2 of the EFD manual).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Situacao {
+    /// "00": regular document.
+    Regular,
+    /// "01": regular document, declared late.
+    RegularExtemporaneo,
+    /// "02": cancelled document.
+    Cancelado,
+    /// "03": cancelled document, declared late.
+    CanceladoExtemporaneo,
+    /// "04": denied NF-e, NFC-e or CT-e.
+    Denegado,
+    /// "05": number discarded (inutilizado).
+    Inutilizado,
+    /// "06": complementary document.
+    Complementar,
+    /// "07": complementary document, declared late.
+    ComplementarExtemporaneo,
+    /// "08": document issued under a special regime or norm.
+    RegimeEspecial,
+}
+
+impl Situacao {
+    /// Parses the COD_SIT field.
+    pub fn from_cod_sit(cod_sit: &str) -> Option<Self> {
+        let situacao = match cod_sit {
+            "00" => Situacao::Regular,
+            "01" => Situacao::RegularExtemporaneo,
+            "02" => Situacao::Cancelado,
+            "03" => Situacao::CanceladoExtemporaneo,
+            "04" => Situacao::Denegado,
+            "05" => Situacao::Inutilizado,
+            "06" => Situacao::Complementar,
+            "07" => Situacao::ComplementarExtemporaneo,
+            "08" => Situacao::RegimeEspecial,
+            _ => return None,
+        };
+        Some(situacao)
+    }
+
+    /// Returns `true` for cancelled documents (COD_SIT 02 and 03).
+    pub fn cancelado(self) -> bool {
+        matches!(self, Situacao::Cancelado | Situacao::CanceladoExtemporaneo)
+    }
+}
+
+/// A key cited in the key field of a C100, D100 or C500 record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocumentoChave {
+    pub chave: Chave44,
+    /// Line of the record.
+    pub linha: usize,
+    /// Record type: "C100", "D100" or "C500".
+    pub registro: String,
+    /// Type of operation (IND_OPER).
+    pub ind_oper: String,
+    /// Issuer (IND_EMIT).
+    pub ind_emit: String,
+    /// Document situation (COD_SIT).
+    pub cod_sit: String,
+    /// Participant code (COD_PART).
+    pub cod_part: String,
+    /// Document model (COD_MOD).
+    pub cod_mod: String,
+    /// Series (SER).
+    pub ser: String,
+    /// Document number (NUM_DOC).
+    pub num_doc: String,
+    /// Date of the document (DT_DOC), as `ddmmaaaa`.
+    pub dt_doc: String,
+    /// The 0150 participant of `cod_part`, resolved once the whole file was read.
+    pub participante: Option<Participante>,
+}
+
+impl DocumentoChave {
+    /// The key occurrence of a document, if the document has a valid key.
+    pub fn new(documento: &DocumentoFiscal, linha: usize) -> Option<Self> {
+        Some(DocumentoChave {
+            chave: documento.chave?,
+            linha,
+            registro: documento.registro.clone(),
+            ind_oper: documento.ind_oper.clone(),
+            ind_emit: documento.ind_emit.clone(),
+            cod_sit: documento.cod_sit.clone(),
+            cod_part: documento.cod_part.clone(),
+            cod_mod: documento.cod_mod.clone(),
+            ser: documento.ser.clone(),
+            num_doc: documento.num_doc.clone(),
+            dt_doc: documento.dt_doc.clone(),
+            participante: None,
+        })
+    }
+
+    /// Returns `true` if the document was issued by a third party (IND_EMIT = 1).
+    pub fn emissao_terceiros(&self) -> bool {
+        self.ind_emit == "1"
+    }
+
+    pub fn operacao(&self) -> Option<Operacao> {
+        Operacao::from_ind_oper(&self.ind_oper)
+    }
+
+    pub fn situacao(&self) -> Option<Situacao> {
+        Situacao::from_cod_sit(&self.cod_sit)
+    }
+}
+
+/// A fiscal document cited by a C100, D100 or C500 record, with its key.
+///
+/// EFD Contribuições layouts:
+///
+/// `|C100|IND_OPER|IND_EMIT|COD_PART|COD_MOD|COD_SIT|SER|NUM_DOC|CHV_NFE|DT_DOC|...|`
+///
+/// `|D100|IND_OPER|IND_EMIT|COD_PART|COD_MOD|COD_SIT|SER|SUB|NUM_DOC|CHV_CTE|DT_DOC|...|`
+///
+/// `|C500|COD_PART|COD_MOD|COD_SIT|SER|SUB|NUM_DOC|DT_DOC|DT_ENT|VL_DOC|VL_ICMS|COD_INF|VL_PIS|VL_COFINS|CHV_DOCe|`
+///
+/// C500 records of versions before 006 end at VL_COFINS and have no key field.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DocumentoFiscal {
+    /// Record type: "C100", "D100" or "C500".
+    pub registro: String,
+    /// Type of operation (IND_OPER): "0" entrada, "1" saída. C500 records are always entries.
+    pub ind_oper: String,
+    /// Issuer (IND_EMIT): "0" own issuance, "1" third party. C500 documents are issued by third parties.
+    pub ind_emit: String,
+    /// Participant code (COD_PART), see `Participante`.
+    pub cod_part: String,
+    /// Document model (COD_MOD).
+    pub cod_mod: String,
+    /// Document situation (COD_SIT).
+    pub cod_sit: String,
+    /// Series (SER).
+    pub ser: String,
+    /// Document number (NUM_DOC).
+    pub num_doc: String,
+    /// Key of the document (CHV_NFE, CHV_CTE or CHV_DOCe), if informed and valid.
+    pub chave: Option<Chave44>,
+    /// Date of the document (DT_DOC), as `ddmmaaaa`.
+    pub dt_doc: String,
+}
+
+impl DocumentoFiscal {
+    /// Builds the document from a record with a key field: the first field
+    /// whose name starts with `CHV_` (see `LayoutDefinition`).
+    ///
+    /// Records without IND_OPER and IND_EMIT (like C500) are entries issued by third parties.
+    /// Returns `None` for records without a key field.
+    pub fn from_registro(registro: &Registro) -> Option<Self> {
+        let chave = registro.campo_com_prefixo("CHV_")?;
+        let campo_ou =
+            |nome: &str, padrao: &str| registro.campo(nome).unwrap_or(padrao).to_string();
+
+        Some(DocumentoFiscal {
+            registro: registro.nome().to_string(),
+            ind_oper: campo_ou("IND_OPER", "0"),
+            ind_emit: campo_ou("IND_EMIT", "1"),
+            cod_part: registro.texto("COD_PART"),
+            cod_mod: registro.texto("COD_MOD"),
+            cod_sit: registro.texto("COD_SIT"),
+            ser: registro.texto("SER"),
+            num_doc: registro.texto("NUM_DOC"),
+            chave: Chave44::from_bytes(chave.as_bytes()),
+            dt_doc: registro.texto("DT_DOC"),
+        })
+    }
+
+    /// Returns `true` if the document was issued by a third party (IND_EMIT = 1).
+    pub fn emissao_terceiros(&self) -> bool {
+        self.ind_emit == "1"
+    }
+}
@@ -0,0 +1,419 @@
+use crate::{
+    codigos::{nome_modelo, nome_tp_emis},
+    error::{MyError, MyResult},
+    Chave44, ClasseChave, EfdFileKeys, KeySink,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// A key cited by a document of a 0150 participant.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct ChaveParticipante {
+    pub participante: String,
+    pub cnpj_participante: String,
+    pub uf_participante: String,
+    pub chave: String,
+    pub registro: String,
+    pub linha: usize,
+    pub arquivo: String,
+}
+
+/// Collects the keys cited by C100/D100/C500 documents and writes them as CSV,
+/// sorted by participant name (keys by supplier or customer).
+///
+/// Documents whose COD_PART has no 0150 participant are listed with an empty name.
+pub struct ParticipanteReportSink<W: Write> {
+    writer: W,
+    linhas: Vec<ChaveParticipante>,
+}
+
+impl<W: Write> ParticipanteReportSink<W> {
+    pub fn new(writer: W) -> Self {
+        ParticipanteReportSink {
+            writer,
+            linhas: Vec::new(),
+        }
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl ParticipanteReportSink<BufWriter<File>> {
+    /// Creates the report file.
+    pub fn create(path: &Path) -> MyResult<Self> {
+        let file =
+            File::create(path).map_err(|e| MyError::FileWriteError(path.to_path_buf(), e))?;
+        Ok(ParticipanteReportSink::new(BufWriter::new(file)))
+    }
+}
+
+impl<W: Write> KeySink for ParticipanteReportSink<W> {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        let arquivo = file_keys.path.display().to_string();
+
+        for documento in &file_keys.documentos {
+            let participante = documento.participante.as_ref();
+            self.linhas.push(ChaveParticipante {
+                participante: participante.map(|p| p.nome.clone()).unwrap_or_default(),
+                cnpj_participante: participante
+                    .and_then(|p| p.cnpj_ou_cpf())
+                    .unwrap_or_default(),
+                uf_participante: participante
+                    .and_then(|p| p.uf())
+                    .unwrap_or_default()
+                    .to_string(),
+                chave: documento.chave.to_string(),
+                registro: documento.registro.clone(),
+                linha: documento.linha,
+                arquivo: arquivo.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        self.linhas.sort();
+        let mut writer = csv::Writer::from_writer(&mut self.writer);
+        for linha in self.linhas.drain(..) {
+            writer.serialize(linha)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Periods and files where a key was declared.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CicloChave {
+    pub chave: Chave44,
+    /// First and last periods (`aaaa-mm`, from DT_INI of record 0000) where the key was found.
+    pub primeiro_periodo: String,
+    pub ultimo_periodo: String,
+    /// Number of distinct periods.
+    pub periodos: usize,
+    /// Number of files.
+    pub arquivos: usize,
+}
+
+/// Collects the periods of each key and writes one CSV row per key, sorted by key.
+///
+/// A purchase key declared in the EFDs of several months may indicate that the
+/// credit was taken more than once: with `apenas_duplicados`, only keys found
+/// in two or more periods are written. Files without a 0000 record count only as files.
+pub struct CicloVidaSink<W: Write> {
+    writer: W,
+    apenas_duplicados: bool,
+    chaves: BTreeMap<Chave44, (BTreeSet<String>, usize)>,
+}
+
+impl<W: Write> CicloVidaSink<W> {
+    pub fn new(writer: W, apenas_duplicados: bool) -> Self {
+        CicloVidaSink {
+            writer,
+            apenas_duplicados,
+            chaves: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl CicloVidaSink<BufWriter<File>> {
+    /// Creates the report file.
+    pub fn create(path: &Path, apenas_duplicados: bool) -> MyResult<Self> {
+        let file =
+            File::create(path).map_err(|e| MyError::FileWriteError(path.to_path_buf(), e))?;
+        Ok(CicloVidaSink::new(BufWriter::new(file), apenas_duplicados))
+    }
+}
+
+impl<W: Write> KeySink for CicloVidaSink<W> {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        let periodo = file_keys.header.as_ref().and_then(|h| h.periodo());
+
+        for &chave in &file_keys.chaves {
+            let (periodos, arquivos) = self.chaves.entry(chave).or_default();
+            periodos.extend(periodo.clone());
+            *arquivos += 1;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        let mut writer = csv::Writer::from_writer(&mut self.writer);
+
+        for (chave, (periodos, arquivos)) in std::mem::take(&mut self.chaves) {
+            if self.apenas_duplicados && periodos.len() < 2 {
+                continue;
+            }
+            writer.serialize(CicloChave {
+                chave,
+                primeiro_periodo: periodos.first().cloned().unwrap_or_default(),
+                ultimo_periodo: periodos.last().cloned().unwrap_or_default(),
+                periodos: periodos.len(),
+                arquivos,
+            })?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// An access key issued in contingency.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChaveContingencia {
+    pub chave: Chave44,
+    /// Document model, e.g. "NF-e".
+    pub modelo: String,
+    /// Type of issue (tpEmis) and its name, e.g. 6 and "SVC-AN".
+    pub tp_emis: u8,
+    pub tipo: String,
+    /// Month of issue (`aaaa-mm`, from the AAMM of the key).
+    pub emissao: String,
+    /// First period (`aaaa-mm`, from record 0000) and file where the key was declared.
+    pub periodo: String,
+    pub arquivo: String,
+}
+
+/// Collects the access keys issued in contingency (see `Chave44::contingencia`)
+/// and writes one CSV row per key, sorted by key.
+///
+/// Contingency documents must be authorized afterwards; the report lists
+/// the keys whose authorization must be confirmed. Keys whose type of issue is
+/// not defined for their model follow, also sorted by key, with the type `desconhecido`.
+pub struct ContingenciaReportSink<W: Write> {
+    writer: W,
+    /// First (period, file) of each key.
+    chaves: BTreeMap<Chave44, (String, String)>,
+}
+
+impl<W: Write> ContingenciaReportSink<W> {
+    pub fn new(writer: W) -> Self {
+        ContingenciaReportSink {
+            writer,
+            chaves: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl ContingenciaReportSink<BufWriter<File>> {
+    /// Creates the report file.
+    pub fn create(path: &Path) -> MyResult<Self> {
+        let file =
+            File::create(path).map_err(|e| MyError::FileWriteError(path.to_path_buf(), e))?;
+        Ok(ContingenciaReportSink::new(BufWriter::new(file)))
+    }
+}
+
+impl<W: Write> KeySink for ContingenciaReportSink<W> {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        let periodo = file_keys
+            .header
+            .as_ref()
+            .and_then(|h| h.periodo())
+            .unwrap_or_default();
+        let arquivo = file_keys.path.display().to_string();
+
+        let contingencia = file_keys
+            .chaves
+            .iter()
+            .filter(|chave| chave.classe() == ClasseChave::ChaveAcesso)
+            .filter(|chave| chave.contingencia() || !chave.tp_emis_conhecido());
+
+        for &chave in contingencia {
+            let ocorrencia = (periodo.clone(), arquivo.clone());
+            // Files arrive in any order: keep the first period (then file) of the key.
+            let primeira = self
+                .chaves
+                .entry(chave)
+                .or_insert_with(|| ocorrencia.clone());
+            if ocorrencia < *primeira {
+                *primeira = ocorrencia;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        let mut writer = csv::Writer::from_writer(&mut self.writer);
+
+        let (contingencia, desconhecidas): (Vec<_>, Vec<_>) = std::mem::take(&mut self.chaves)
+            .into_iter()
+            .partition(|(chave, _)| chave.contingencia());
+
+        for (chave, (periodo, arquivo)) in contingencia.into_iter().chain(desconhecidas) {
+            let aamm = chave.aamm();
+            writer.serialize(ChaveContingencia {
+                chave,
+                modelo: nome_modelo(chave.modelo()).unwrap_or_default().to_string(),
+                tp_emis: chave.tp_emis(),
+                tipo: nome_tp_emis(chave.modelo(), chave.tp_emis())
+                    .unwrap_or("desconhecido")
+                    .to_string(),
+                emissao: format!("20{}-{}", &aamm[..2], &aamm[2..]),
+                periodo,
+                arquivo,
+            })?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output relatorio_tests
+#[cfg(test)]
+mod relatorio_tests {
+    use super::*;
+    use crate::read_efd_file;
+    use crate::test_utils::chave_com_dv;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn keys_are_listed_by_participant_name() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("PISCOFINS_0150.txt");
+
+        let chave1 = "35250612345678000190550010000001231123456782";
+        let chave2 = "35250612345678000190550010000001241123456789";
+        fs::write(
+            &path,
+            format!(
+                "|0000|006|0|||01062025|30062025|EMPRESA|99999999000191|SP|\n\
+                 |0150|F1|ZETA LTDA|01058|12345678000190|||3550308|\n\
+                 |0150|F2|ALFA SA|01058||12345678909||4106902|\n\
+                 |C100|0|1|F1|55|00|1|123|{chave1}|15062025|\n\
+                 |C100|0|1|F2|55|00|1|124|{chave2}|15062025|\n\
+                 |9999|6|\n"
+            ),
+        )?;
+
+        let file_keys = read_efd_file(&path)?;
+        let mut report = ParticipanteReportSink::new(Vec::new());
+        report.write_file_keys(&file_keys)?;
+        report.finish()?;
+
+        let csv = String::from_utf8(report.into_inner()).unwrap_or_default();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            [
+                "participante,cnpj_participante,uf_participante,chave,registro,linha,arquivo",
+                &format!("ALFA SA,12345678909,PR,{chave2},C100,5,{}", path.display()),
+                &format!(
+                    "ZETA LTDA,12345678000190,SP,{chave1},C100,4,{}",
+                    path.display()
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keys_declared_in_more_than_one_period() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let chave1 = "35250612345678000190550010000001231123456782";
+        let chave2 = "35250612345678000190550010000001241123456789";
+
+        let mut report = CicloVidaSink::new(Vec::new(), true);
+        for (nome, dt_ini, chaves) in [
+            ("PISCOFINS_06.txt", "01062025", vec![chave1, chave2]),
+            ("PISCOFINS_07.txt", "01072025", vec![chave1]),
+            ("PISCOFINS_07_RET.txt", "01072025", vec![chave1]),
+        ] {
+            let path = temp_dir.path().join(nome);
+            let linhas: String = chaves
+                .iter()
+                .map(|chave| format!("|C100|0|1|F1|55|00|1|1|{chave}|15062025|\n"))
+                .collect();
+            fs::write(
+                &path,
+                format!("|0000|006|0|||{dt_ini}|30062025|EMPRESA|99999999000191|SP|\n{linhas}"),
+            )?;
+            report.write_file_keys(&read_efd_file(&path)?)?;
+        }
+        report.finish()?;
+
+        let csv = String::from_utf8(report.into_inner()).unwrap_or_default();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            [
+                "chave,primeiro_periodo,ultimo_periodo,periodos,arquivos",
+                &format!("{chave1},2025-06,2025-07,2,3"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keys_issued_in_contingency() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let normal = "35250612345678000190550010000001231123456782";
+        let svc_an = "35250612345678000190550010000001236123456783";
+        let offline = "35250612345678000190650010000001249123456788";
+        // Type 8 (SVC-SP) is only defined for the CT-e.
+        let desconhecida = chave_com_dv("3525061234567800019055001000000125812345678")?;
+
+        let mut report = ContingenciaReportSink::new(Vec::new());
+        for (nome, dt_ini) in [
+            ("PISCOFINS_07.txt", "01072025"),
+            ("PISCOFINS_06.txt", "01062025"),
+        ] {
+            let path = temp_dir.path().join(nome);
+            fs::write(
+                &path,
+                format!(
+                    "|0000|006|0|||{dt_ini}|30062025|EMPRESA|99999999000191|SP|\n\
+                     |C100|0|1|F1|55|00|1|123|{normal}|15062025|\n\
+                     |C100|0|1|F1|55|00|1|123|{svc_an}|15062025|\n\
+                     |C100|1|0|C1|65|00|1|124|{offline}|15062025|\n\
+                     |C100|0|1|F1|55|00|1|125|{desconhecida}|15062025|\n"
+                ),
+            )?;
+            report.write_file_keys(&read_efd_file(&path)?)?;
+        }
+        report.finish()?;
+
+        let arquivo = temp_dir.path().join("PISCOFINS_06.txt");
+        let csv = String::from_utf8(report.into_inner()).unwrap_or_default();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            [
+                "chave,modelo,tp_emis,tipo,emissao,periodo,arquivo",
+                &format!(
+                    "{svc_an},NF-e,6,SVC-AN,2025-06,2025-06,{}",
+                    arquivo.display()
+                ),
+                &format!(
+                    "{offline},NFC-e,9,offline NFC-e,2025-06,2025-06,{}",
+                    arquivo.display()
+                ),
+                &format!(
+                    "{desconhecida},NF-e,8,desconhecido,2025-06,2025-06,{}",
+                    arquivo.display()
+                ),
+            ]
+        );
+        Ok(())
+    }
+}
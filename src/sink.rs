@@ -0,0 +1,1124 @@
+use crate::{
+    error::{MyError, MyResult},
+    Chave44, ChaveUrl, ClasseChave, DocumentoChave, EfdFileKeys, Emissao, Operacao, Situacao,
+    PACKED_LEN,
+};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+};
+use tempfile::TempDir;
+
+/// Number of (key, file) records kept in memory before a sorted run
+/// is spilled to disk by `ExternalDedupSink`.
+pub const EXTERNAL_RUN_CAPACITY: usize = 1 << 22;
+
+/// Maximum number of runs merged at once by `ExternalDedupSink`.
+const MAX_FAN_IN: usize = 64;
+
+/// Size of one record in a run file: packed key + file index
+/// + number of occurrences (u32, little-endian)
+/// + position of the provenance of the key (u64, little-endian).
+const RECORD_LEN: usize = PACKED_LEN + 16;
+
+/// Position of a key without provenance (not formatted, not in a URL, without documents).
+const SEM_PROCEDENCIA: u64 = u64::MAX;
+
+/// Output format of the extracted keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Formato {
+    /// One key per line.
+    Texto,
+    /// Comma-separated values with a header line.
+    Csv,
+    /// Newline-delimited JSON: one object per line.
+    Ndjson,
+}
+
+impl Formato {
+    /// Default file extension for the format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Formato::Texto => "txt",
+            Formato::Csv => "csv",
+            Formato::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// How keys found in more than one EFD file are removed from the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Dedup {
+    /// Keep the keys already written in a hash set and write only new keys,
+    /// as soon as each file is processed.
+    Memoria,
+    /// Spill sorted runs to temporary files and merge them at the end.
+    /// Uses little memory and writes the keys in sorted order.
+    Externa,
+}
+
+/// Which files a key must be unique across.
+///
+/// A key declared in the EFDs of two different companies is itself an audit finding:
+/// `cnpj` keeps one occurrence per declaring company, and the `ocorrencias` column
+/// of the structured formats counts the files of the same scope where the key was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Escopo {
+    /// Each key once in the whole run.
+    Execucao,
+    /// Each key once per declaring CNPJ (record 0000).
+    Cnpj,
+    /// Each key once per file.
+    Arquivo,
+    /// Each key once per file, counting the files of the whole run.
+    Nenhum,
+}
+
+impl Escopo {
+    /// Files with the same group share the keys already written.
+    ///
+    /// Returns `None` for `Nenhum`: every file is written.
+    pub fn grupo(self, file_keys: &EfdFileKeys) -> Option<String> {
+        match self {
+            Escopo::Execucao => Some(String::new()),
+            Escopo::Cnpj => Some(
+                file_keys
+                    .header
+                    .as_ref()
+                    .map(|header| header.cnpj.clone())
+                    .unwrap_or_default(),
+            ),
+            Escopo::Arquivo => Some(file_keys.path.display().to_string()),
+            Escopo::Nenhum => None,
+        }
+    }
+}
+
+/// A destination for the keys extracted from EFD files.
+///
+/// The keys of each file are sent to the sink as soon as the file is processed,
+/// so results can be written (and monitored) while the run is in progress.
+pub trait KeySink {
+    /// Receives the keys of one EFD file.
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()>;
+
+    /// Called once, after all files have been processed.
+    fn finish(&mut self) -> MyResult<()>;
+}
+
+impl<S: KeySink + ?Sized> KeySink for Box<S> {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        (**self).write_file_keys(file_keys)
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        (**self).finish()
+    }
+}
+
+/// Forwards every file to each sink of the list, in order.
+impl KeySink for Vec<Box<dyn KeySink>> {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        self.iter_mut()
+            .try_for_each(|sink| sink.write_file_keys(file_keys))
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        self.iter_mut().try_for_each(|sink| sink.finish())
+    }
+}
+
+/// A key and the file where it was found, as written by the structured formats.
+#[derive(Debug, Serialize)]
+struct KeyRecord {
+    chave: Chave44,
+    classe: ClasseChave,
+    /// Number of files of the deduplication scope where the key was found.
+    ocorrencias: usize,
+    /// Issued by the declaring company (propria) or by a third party (terceiros).
+    emissao: Option<Emissao>,
+    /// `true` if the key was written in groups (spaces, dots or dashes).
+    formatada: bool,
+    /// Portal of the query URL where the key was found (see `portal_da_url`).
+    portal: Option<String>,
+    /// Record (C100, D100, C500) of the first document that cites the key.
+    registro: Option<String>,
+    /// IND_OPER and COD_SIT of that document.
+    operacao: Option<Operacao>,
+    situacao: Option<Situacao>,
+    /// Name, CNPJ (or CPF) and UF of the 0150 participant of that document.
+    participante: Option<String>,
+    cnpj_participante: Option<String>,
+    uf_participante: Option<String>,
+    arquivo: String,
+}
+
+impl KeyRecord {
+    fn from_file_keys(file_keys: &EfdFileKeys) -> impl Iterator<Item = KeyRecord> + '_ {
+        let arquivo = file_keys.path.display().to_string();
+        file_keys.chaves.iter().map(move |&chave| {
+            let documento = file_keys.documentos_da_chave(&chave).first();
+            let participante = documento.and_then(|doc| doc.participante.as_ref());
+
+            KeyRecord {
+                chave,
+                classe: chave.classe(),
+                ocorrencias: file_keys.ocorrencias(&chave),
+                emissao: file_keys.emissao(&chave),
+                formatada: file_keys.is_formatada(&chave),
+                portal: file_keys.portal(&chave).map(str::to_string),
+                registro: documento.map(|doc| doc.registro.clone()),
+                operacao: documento.and_then(|doc| doc.operacao()),
+                situacao: documento.and_then(|doc| doc.situacao()),
+                participante: participante.map(|p| p.nome.clone()),
+                cnpj_participante: participante.and_then(|p| p.cnpj_ou_cpf()),
+                uf_participante: participante.and_then(|p| p.uf()).map(str::to_string),
+                arquivo: arquivo.clone(),
+            }
+        })
+    }
+}
+
+/// Writes one key per line.
+pub struct TextSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TextSink<W> {
+    pub fn new(writer: W) -> Self {
+        TextSink { writer }
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> KeySink for TextSink<W> {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        for chave in &file_keys.chaves {
+            writeln!(self.writer, "{chave}")?;
+        }
+        // Make the keys of each processed file visible in the output right away.
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes one row per key, with a header line (see `KeyRecord`).
+pub struct CsvSink<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn new(writer: W) -> Self {
+        CsvSink {
+            writer: csv::Writer::from_writer(writer),
+        }
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> MyResult<W> {
+        self.writer
+            .into_inner()
+            .map_err(|e| MyError::IoError(io::Error::other(e.to_string())))
+    }
+}
+
+impl<W: Write> KeySink for CsvSink<W> {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        for record in KeyRecord::from_file_keys(file_keys) {
+            self.writer.serialize(record)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes one JSON object per line: `{"chave":"...","classe":"...",...,"arquivo":"..."}`.
+pub struct NdjsonSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> NdjsonSink<W> {
+    pub fn new(writer: W) -> Self {
+        NdjsonSink { writer }
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> KeySink for NdjsonSink<W> {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        for record in KeyRecord::from_file_keys(file_keys) {
+            serde_json::to_writer(&mut self.writer, &record)?;
+            writeln!(self.writer)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Creates the output file and the sink for the chosen format.
+pub fn create_sink<P>(formato: Formato, output_file: P) -> MyResult<Box<dyn KeySink>>
+where
+    P: AsRef<Path>,
+{
+    let path = output_file.as_ref();
+    let file = File::create(path).map_err(|e| MyError::FileWriteError(path.to_path_buf(), e))?;
+    let writer = BufWriter::new(file);
+
+    let sink: Box<dyn KeySink> = match formato {
+        Formato::Texto => Box::new(TextSink::new(writer)),
+        Formato::Csv => Box::new(CsvSink::new(writer)),
+        Formato::Ndjson => Box::new(NdjsonSink::new(writer)),
+    };
+
+    Ok(sink)
+}
+
+/// Wraps `sink` with the chosen deduplication scope and strategy.
+///
+/// `dedup` applies to `Escopo::Execucao`; the other scopes are deduplicated in memory.
+/// With `contar`, the files are spilled to disk until the end of the run to count
+/// the occurrences of each key (see `ContagemSink`); otherwise each file is written
+/// as it arrives. `ExternalDedupSink` always counts them while merging its runs.
+pub fn with_dedup(
+    dedup: Dedup,
+    escopo: Escopo,
+    contar: bool,
+    sink: Box<dyn KeySink>,
+) -> MyResult<Box<dyn KeySink>> {
+    // Each file is already sorted and without duplicates.
+    let sink: Box<dyn KeySink> = match (escopo, dedup) {
+        (Escopo::Arquivo | Escopo::Nenhum, _) => sink,
+        (Escopo::Execucao, Dedup::Externa) => {
+            Box::new(ExternalDedupSink::new(sink, EXTERNAL_RUN_CAPACITY)?)
+        }
+        (escopo, _) => Box::new(MemoryDedupSink::with_escopo(sink, escopo)),
+    };
+
+    // The external sort counts the occurrences itself while merging the runs.
+    let contar = contar && !(escopo == Escopo::Execucao && dedup == Dedup::Externa);
+
+    let sink: Box<dyn KeySink> = if contar && escopo != Escopo::Arquivo {
+        Box::new(ContagemSink::new(sink, escopo)?)
+    } else {
+        sink
+    };
+    Ok(sink)
+}
+
+/// Counts the files where each key was found, within the groups of `escopo`,
+/// and forwards every file with its `ocorrencias` at the end of the run.
+///
+/// Only the counts are kept in memory: the files are spilled (as JSON lines, in the
+/// order received) to a temporary file and read back at `finish`.
+pub struct ContagemSink<S: KeySink> {
+    inner: S,
+    escopo: Escopo,
+    contagens: HashMap<(String, Chave44), usize>,
+    arquivos: BufWriter<File>,
+}
+
+impl<S: KeySink> ContagemSink<S> {
+    pub fn new(inner: S, escopo: Escopo) -> MyResult<Self> {
+        Ok(ContagemSink {
+            inner,
+            escopo,
+            contagens: HashMap::new(),
+            arquivos: BufWriter::new(tempfile::tempfile()?),
+        })
+    }
+}
+
+impl<S: KeySink> KeySink for ContagemSink<S> {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        let grupo = self.escopo.grupo(file_keys).unwrap_or_default();
+        for &chave in &file_keys.chaves {
+            *self.contagens.entry((grupo.clone(), chave)).or_default() += 1;
+        }
+
+        serde_json::to_writer(&mut self.arquivos, file_keys)?;
+        self.arquivos.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        self.arquivos.flush()?;
+        let mut file = self.arquivos.get_ref().try_clone()?;
+        file.seek(SeekFrom::Start(0))?;
+
+        for line in BufReader::new(file).lines() {
+            let mut file_keys: EfdFileKeys = serde_json::from_str(&line?)?;
+            let grupo = self.escopo.grupo(&file_keys).unwrap_or_default();
+            file_keys.ocorrencias = file_keys
+                .chaves
+                .iter()
+                .map(|&chave| (chave, self.contagens[&(grupo.clone(), chave)]))
+                .collect();
+            self.inner.write_file_keys(&file_keys)?;
+        }
+
+        self.contagens.clear();
+        self.inner.finish()
+    }
+}
+
+/// Forwards only the keys not seen in previously processed files of the same
+/// group (see `Escopo::grupo`).
+///
+/// Each key is written once per group, in the file where it was first received.
+pub struct MemoryDedupSink<S: KeySink> {
+    inner: S,
+    escopo: Escopo,
+    vistas: HashMap<String, HashSet<Chave44>>,
+}
+
+impl<S: KeySink> MemoryDedupSink<S> {
+    /// Each key once in the whole run.
+    pub fn new(inner: S) -> Self {
+        MemoryDedupSink::with_escopo(inner, Escopo::Execucao)
+    }
+
+    pub fn with_escopo(inner: S, escopo: Escopo) -> Self {
+        MemoryDedupSink {
+            inner,
+            escopo,
+            vistas: HashMap::new(),
+        }
+    }
+}
+
+impl<S: KeySink> KeySink for MemoryDedupSink<S> {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        let Some(grupo) = self.escopo.grupo(file_keys) else {
+            return self.inner.write_file_keys(file_keys);
+        };
+
+        let vistas = self.vistas.entry(grupo).or_default();
+        let chaves: Vec<Chave44> = file_keys
+            .chaves
+            .iter()
+            .filter(|&&chave| vistas.insert(chave))
+            .copied()
+            .collect();
+
+        if chaves.is_empty() {
+            return Ok(());
+        }
+
+        self.inner.write_file_keys(&file_keys.with_chaves(chaves))
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        self.inner.finish()
+    }
+}
+
+/// Deduplicates keys with an external merge sort.
+///
+/// (key, file index) records are buffered and spilled as sorted runs to a
+/// temporary directory. At the end, runs are merged and each key is forwarded
+/// once, in sorted order, together with the first file where it was received
+/// and the number of files where it was found (`ocorrencias`).
+///
+/// The provenance of each key (formatted, portal, documents) is spilled as well,
+/// to a file of JSON lines whose position goes in the run record, and read back
+/// only for the forwarded keys.
+pub struct ExternalDedupSink<S: KeySink> {
+    inner: S,
+    temp_dir: TempDir,
+    run_capacity: usize,
+    buffer: Vec<(Chave44, u32, u64)>,
+    runs: Vec<PathBuf>,
+    /// Number of run files created so far: names are never reused, so a merged
+    /// run never overwrites one of its inputs.
+    runs_criados: usize,
+    /// Each received file, with only its path and header.
+    arquivos: Vec<EfdFileKeys>,
+    procedencias: ProcedenciaWriter,
+}
+
+impl<S: KeySink> ExternalDedupSink<S> {
+    /// `run_capacity` is the number of records kept in memory before spilling a run.
+    pub fn new(inner: S, run_capacity: usize) -> MyResult<Self> {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("efd-chaves-runs")
+            .tempdir()?;
+        let procedencias = ProcedenciaWriter::create(&temp_dir.path().join(PROCEDENCIAS))?;
+
+        Ok(ExternalDedupSink {
+            inner,
+            temp_dir,
+            run_capacity: run_capacity.max(1),
+            buffer: Vec::new(),
+            runs: Vec::new(),
+            runs_criados: 0,
+            arquivos: Vec::new(),
+            procedencias,
+        })
+    }
+
+    fn new_run_path(&mut self) -> PathBuf {
+        let path = self
+            .temp_dir
+            .path()
+            .join(format!("run-{:06}.bin", self.runs_criados));
+        self.runs_criados += 1;
+        path
+    }
+
+    /// Sorts the buffered records and writes them as a new run.
+    fn spill(&mut self) -> MyResult<()> {
+        let records = sort_records(mem::take(&mut self.buffer));
+
+        let path = self.new_run_path();
+        let mut writer = RunWriter::create(&path)?;
+        records
+            .into_iter()
+            .try_for_each(|record| writer.write(record))?;
+        writer.finish()?;
+
+        self.runs.push(path);
+        Ok(())
+    }
+
+    /// Merges groups of runs until at most `MAX_FAN_IN` remain.
+    fn reduce_runs(&mut self) -> MyResult<()> {
+        while self.runs.len() > MAX_FAN_IN {
+            let runs = mem::take(&mut self.runs);
+
+            for group in runs.chunks(MAX_FAN_IN) {
+                let path = self.new_run_path();
+                let mut writer = RunWriter::create(&path)?;
+                merge_runs(group, |record| writer.write(record))?;
+                writer.finish()?;
+                self.runs.push(path);
+
+                // The merged runs are no longer needed.
+                for run in group {
+                    fs::remove_file(run)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: KeySink> KeySink for ExternalDedupSink<S> {
+    fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+        let file_idx = self.arquivos.len() as u32;
+        self.arquivos.push(EfdFileKeys {
+            path: file_keys.path.clone(),
+            header: file_keys.header.clone(),
+            ..Default::default()
+        });
+
+        for &chave in &file_keys.chaves {
+            let posicao = self.procedencias.write(file_keys, &chave)?;
+            self.buffer.push((chave, file_idx, posicao));
+        }
+
+        if self.buffer.len() >= self.run_capacity {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        self.procedencias.finish()?;
+        if !self.runs.is_empty() {
+            if !self.buffer.is_empty() {
+                self.spill()?;
+            }
+            self.reduce_runs()?;
+        }
+
+        let mut batch = Batch {
+            arquivos: &self.arquivos,
+            procedencias: ProcedenciaReader::open(&self.temp_dir.path().join(PROCEDENCIAS))?,
+            file_keys: EfdFileKeys::default(),
+            file_idx: 0,
+        };
+
+        if self.runs.is_empty() {
+            // Everything fits in memory: no merge needed.
+            for record in sort_records(mem::take(&mut self.buffer)) {
+                batch.push(record, &mut self.inner)?;
+            }
+        } else {
+            let inner = &mut self.inner;
+            merge_runs(&self.runs, |record| batch.push(record, inner))?;
+        }
+
+        batch.flush(&mut self.inner)?;
+        self.inner.finish()
+    }
+}
+
+/// A key of a run: the first file where it was received, the position of its
+/// provenance in that file and the number of files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct RunRecord {
+    chave: Chave44,
+    file_idx: u32,
+    posicao: u64,
+    ocorrencias: u32,
+}
+
+/// Sorts (key, file index, position) triples by key, then by file index, keeping one
+/// record per key with the first file and the number of files where it was found.
+fn sort_records(mut triples: Vec<(Chave44, u32, u64)>) -> Vec<RunRecord> {
+    triples.sort_unstable();
+
+    let mut records: Vec<RunRecord> = Vec::with_capacity(triples.len());
+    for (chave, file_idx, posicao) in triples {
+        match records.last_mut() {
+            Some(last) if last.chave == chave => last.ocorrencias += 1,
+            _ => records.push(RunRecord {
+                chave,
+                file_idx,
+                posicao,
+                ocorrencias: 1,
+            }),
+        }
+    }
+    records
+}
+
+/// Name of the file of provenances, in the temporary directory of the runs.
+const PROCEDENCIAS: &str = "procedencias.jsonl";
+
+/// How a key was found in one file: what `KeyRecord` needs besides the path and header.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Procedencia {
+    formatada: bool,
+    portal: Option<String>,
+    documentos: Vec<DocumentoChave>,
+}
+
+impl Procedencia {
+    fn new(file_keys: &EfdFileKeys, chave: &Chave44) -> Self {
+        Procedencia {
+            formatada: file_keys.is_formatada(chave),
+            portal: file_keys.portal(chave).map(str::to_string),
+            documentos: file_keys.documentos_da_chave(chave).to_vec(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.formatada && self.portal.is_none() && self.documentos.is_empty()
+    }
+}
+
+/// Appends the provenance of each key, one JSON line per key.
+struct ProcedenciaWriter {
+    writer: BufWriter<File>,
+    len: u64,
+}
+
+impl ProcedenciaWriter {
+    fn create(path: &Path) -> MyResult<Self> {
+        let file =
+            File::create(path).map_err(|e| MyError::FileWriteError(path.to_path_buf(), e))?;
+        Ok(ProcedenciaWriter {
+            writer: BufWriter::new(file),
+            len: 0,
+        })
+    }
+
+    /// Returns the position of the line written, or `SEM_PROCEDENCIA`
+    /// if the key has nothing to write.
+    fn write(&mut self, file_keys: &EfdFileKeys, chave: &Chave44) -> MyResult<u64> {
+        let procedencia = Procedencia::new(file_keys, chave);
+        if procedencia.is_empty() {
+            return Ok(SEM_PROCEDENCIA);
+        }
+
+        let mut line = serde_json::to_vec(&procedencia)?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+
+        let posicao = self.len;
+        self.len += line.len() as u64;
+        Ok(posicao)
+    }
+
+    fn finish(&mut self) -> MyResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the provenance of a key at the position returned by `ProcedenciaWriter::write`.
+struct ProcedenciaReader {
+    reader: BufReader<File>,
+    line: String,
+}
+
+impl ProcedenciaReader {
+    fn open(path: &Path) -> MyResult<Self> {
+        let file = File::open(path).map_err(|e| MyError::FileReadError(path.to_path_buf(), e))?;
+        Ok(ProcedenciaReader {
+            reader: BufReader::new(file),
+            line: String::new(),
+        })
+    }
+
+    fn read(&mut self, posicao: u64) -> MyResult<Procedencia> {
+        if posicao == SEM_PROCEDENCIA {
+            return Ok(Procedencia::default());
+        }
+
+        self.reader.seek(SeekFrom::Start(posicao))?;
+        self.line.clear();
+        self.reader.read_line(&mut self.line)?;
+        Ok(serde_json::from_str(&self.line)?)
+    }
+}
+
+/// Consecutive merged keys of the same file, forwarded together to the inner sink.
+struct Batch<'a> {
+    /// Path and header of each received file.
+    arquivos: &'a [EfdFileKeys],
+    procedencias: ProcedenciaReader,
+    file_keys: EfdFileKeys,
+    file_idx: u32,
+}
+
+impl Batch<'_> {
+    fn push(&mut self, record: RunRecord, inner: &mut impl KeySink) -> MyResult<()> {
+        if record.file_idx != self.file_idx {
+            self.flush(inner)?;
+            self.file_idx = record.file_idx;
+        }
+
+        let chave = record.chave;
+        let procedencia = self.procedencias.read(record.posicao)?;
+        let file_keys = &mut self.file_keys;
+
+        file_keys.chaves.push(chave);
+        if procedencia.formatada {
+            file_keys.formatadas.push(chave);
+        }
+        if let Some(portal) = procedencia.portal {
+            file_keys.urls.push(ChaveUrl { chave, portal });
+        }
+        file_keys.documentos.extend(procedencia.documentos);
+        if record.ocorrencias > 1 {
+            file_keys
+                .ocorrencias
+                .insert(chave, record.ocorrencias as usize);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, inner: &mut impl KeySink) -> MyResult<()> {
+        if self.file_keys.chaves.is_empty() {
+            return Ok(());
+        }
+
+        let arquivo = &self.arquivos[self.file_idx as usize];
+        let file_keys = EfdFileKeys {
+            path: arquivo.path.clone(),
+            header: arquivo.header.clone(),
+            ..mem::take(&mut self.file_keys)
+        };
+        inner.write_file_keys(&file_keys)
+    }
+}
+
+/// Writes fixed-size records to a run file.
+struct RunWriter {
+    writer: BufWriter<File>,
+}
+
+impl RunWriter {
+    fn create(path: &Path) -> MyResult<Self> {
+        let file =
+            File::create(path).map_err(|e| MyError::FileWriteError(path.to_path_buf(), e))?;
+        Ok(RunWriter {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    fn write(&mut self, record: RunRecord) -> MyResult<()> {
+        self.writer.write_all(&record.chave.to_packed())?;
+        self.writer.write_all(&record.file_idx.to_le_bytes())?;
+        self.writer.write_all(&record.ocorrencias.to_le_bytes())?;
+        self.writer.write_all(&record.posicao.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> MyResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the records of a run file, in order.
+struct RunReader {
+    reader: BufReader<File>,
+}
+
+impl RunReader {
+    fn open(path: &Path) -> MyResult<Self> {
+        let file = File::open(path).map_err(|e| MyError::FileReadError(path.to_path_buf(), e))?;
+        Ok(RunReader {
+            reader: BufReader::new(file),
+        })
+    }
+
+    fn next_record(&mut self) -> MyResult<Option<RunRecord>> {
+        let mut record = [0u8; RECORD_LEN];
+
+        match self.reader.read_exact(&mut record) {
+            Ok(()) => {
+                let mut packed = [0u8; PACKED_LEN];
+                let mut file_idx = [0u8; 4];
+                let mut ocorrencias = [0u8; 4];
+                let mut posicao = [0u8; 8];
+                packed.copy_from_slice(&record[..PACKED_LEN]);
+                file_idx.copy_from_slice(&record[PACKED_LEN..PACKED_LEN + 4]);
+                ocorrencias.copy_from_slice(&record[PACKED_LEN + 4..PACKED_LEN + 8]);
+                posicao.copy_from_slice(&record[PACKED_LEN + 8..]);
+                Ok(Some(RunRecord {
+                    chave: Chave44::from_packed(packed),
+                    file_idx: u32::from_le_bytes(file_idx),
+                    posicao: u64::from_le_bytes(posicao),
+                    ocorrencias: u32::from_le_bytes(ocorrencias),
+                }))
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// K-way merge of sorted runs: calls `emit` once per distinct key, in sorted order,
+/// with the smallest file index found for it and the sum of its occurrences.
+fn merge_runs<F>(runs: &[PathBuf], mut emit: F) -> MyResult<()>
+where
+    F: FnMut(RunRecord) -> MyResult<()>,
+{
+    let mut readers: Vec<RunReader> = runs
+        .iter()
+        .map(|path| RunReader::open(path))
+        .collect::<MyResult<_>>()?;
+
+    let mut heap = BinaryHeap::new();
+    for (run_idx, reader) in readers.iter_mut().enumerate() {
+        if let Some(record) = reader.next_record()? {
+            heap.push(Reverse((record, run_idx)));
+        }
+    }
+
+    let mut atual: Option<RunRecord> = None;
+
+    while let Some(Reverse((record, run_idx))) = heap.pop() {
+        match atual.as_mut() {
+            // Same key in another run: the first file was already popped.
+            Some(atual) if atual.chave == record.chave => {
+                atual.ocorrencias += record.ocorrencias;
+            }
+            _ => {
+                if let Some(anterior) = atual.replace(record) {
+                    emit(anterior)?;
+                }
+            }
+        }
+
+        if let Some(next) = readers[run_idx].next_record()? {
+            heap.push(Reverse((next, run_idx)));
+        }
+    }
+
+    if let Some(ultimo) = atual {
+        emit(ultimo)?;
+    }
+
+    Ok(())
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output sink_tests
+#[cfg(test)]
+mod sink_tests {
+    use super::*;
+    use crate::ChaveUrl;
+
+    /// Collects everything it receives.
+    #[derive(Default)]
+    struct CollectSink {
+        files: Vec<EfdFileKeys>,
+        finished: bool,
+    }
+
+    impl KeySink for &mut CollectSink {
+        fn write_file_keys(&mut self, file_keys: &EfdFileKeys) -> MyResult<()> {
+            self.files.push(file_keys.clone());
+            Ok(())
+        }
+
+        fn finish(&mut self) -> MyResult<()> {
+            self.finished = true;
+            Ok(())
+        }
+    }
+
+    fn file_keys(path: &str, keys: &[u64]) -> MyResult<EfdFileKeys> {
+        Ok(EfdFileKeys {
+            path: PathBuf::from(path),
+            header: None,
+            chaves: keys
+                .iter()
+                .map(|k| format!("{k:044}").parse())
+                .collect::<MyResult<_>>()?,
+            ..Default::default()
+        })
+    }
+
+    fn inputs() -> MyResult<Vec<EfdFileKeys>> {
+        Ok(vec![
+            file_keys("a.txt", &[5, 3, 9, 1])?,
+            file_keys("b.txt", &[2, 3, 7])?,
+            file_keys("c.txt", &[9, 8, 1, 4])?,
+        ])
+    }
+
+    #[test]
+    fn memory_dedup_streams_first_occurrences() -> MyResult<()> {
+        let mut collected = CollectSink::default();
+        let mut sink = MemoryDedupSink::new(&mut collected);
+
+        for input in inputs()? {
+            sink.write_file_keys(&input)?;
+        }
+        sink.finish()?;
+
+        assert!(collected.finished);
+        assert_eq!(
+            collected.files,
+            [
+                file_keys("a.txt", &[5, 3, 9, 1])?,
+                file_keys("b.txt", &[2, 7])?,
+                file_keys("c.txt", &[8, 4])?,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn external_dedup_merges_sorted_runs() -> MyResult<()> {
+        let mut collected = CollectSink::default();
+        // A tiny run capacity forces one run per file.
+        let mut sink = ExternalDedupSink::new(&mut collected, 2)?;
+
+        for input in inputs()? {
+            sink.write_file_keys(&input)?;
+        }
+        sink.finish()?;
+
+        // Keys received in two files are counted while merging.
+        let em_dois = |path: &str, key: u64| -> MyResult<EfdFileKeys> {
+            let mut input = file_keys(path, &[key])?;
+            input.ocorrencias = input.chaves.iter().map(|&chave| (chave, 2)).collect();
+            Ok(input)
+        };
+
+        assert!(collected.finished);
+        assert_eq!(
+            collected.files,
+            [
+                em_dois("a.txt", 1)?,
+                file_keys("b.txt", &[2])?,
+                em_dois("a.txt", 3)?,
+                file_keys("c.txt", &[4])?,
+                file_keys("a.txt", &[5])?,
+                file_keys("b.txt", &[7])?,
+                file_keys("c.txt", &[8])?,
+                em_dois("a.txt", 9)?,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn external_dedup_merges_more_runs_than_the_fan_in() -> MyResult<()> {
+        let mut collected = CollectSink::default();
+        // One run per file: the runs are merged in groups before the final merge.
+        let mut sink = ExternalDedupSink::new(&mut collected, 1)?;
+
+        // Every key is received twice: in "a" files, then in "b" files.
+        let total = MAX_FAN_IN as u64 + 5;
+        for lote in ["a", "b"] {
+            for key in (1..=total).rev() {
+                sink.write_file_keys(&file_keys(&format!("{lote}{key}.txt"), &[key])?)?;
+            }
+        }
+        sink.finish()?;
+
+        assert!(collected.files.iter().all(|file_keys| {
+            let chave = file_keys.chaves[0];
+            file_keys.path.to_string_lossy().starts_with('a') && file_keys.ocorrencias(&chave) == 2
+        }));
+        let chaves: Vec<Chave44> = collected
+            .files
+            .iter()
+            .flat_map(|file_keys| file_keys.chaves.iter().copied())
+            .collect();
+        let expected: Vec<Chave44> = (1..=total)
+            .map(|key| format!("{key:044}").parse())
+            .collect::<MyResult<_>>()?;
+        assert_eq!(chaves, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn dedup_by_cnpj_counts_occurrences() -> MyResult<()> {
+        let com_cnpj = |path: &str, cnpj: &str, keys: &[u64]| -> MyResult<EfdFileKeys> {
+            Ok(EfdFileKeys {
+                header: Some(crate::EfdHeader {
+                    cnpj: cnpj.to_string(),
+                    ..Default::default()
+                }),
+                ..file_keys(path, keys)?
+            })
+        };
+
+        let mut collected = CollectSink::default();
+        let mut sink = ContagemSink::new(
+            MemoryDedupSink::with_escopo(&mut collected, Escopo::Cnpj),
+            Escopo::Cnpj,
+        )?;
+        sink.write_file_keys(&com_cnpj("jan.txt", "A", &[1, 2])?)?;
+        sink.write_file_keys(&com_cnpj("fev.txt", "A", &[1, 3])?)?;
+        sink.write_file_keys(&com_cnpj("jan_b.txt", "B", &[1])?)?;
+        sink.finish()?;
+
+        let written: Vec<(String, Vec<usize>)> = collected
+            .files
+            .iter()
+            .map(|file_keys| {
+                (
+                    file_keys.path.display().to_string(),
+                    file_keys
+                        .chaves
+                        .iter()
+                        .map(|chave| file_keys.ocorrencias(chave))
+                        .collect(),
+                )
+            })
+            .collect();
+
+        // Key 1: twice for CNPJ A (written once), once for CNPJ B.
+        assert_eq!(
+            written,
+            [
+                ("jan.txt".to_string(), vec![2, 1]),
+                ("fev.txt".to_string(), vec![1]),
+                ("jan_b.txt".to_string(), vec![1]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dedup_keeps_the_provenance_of_forwarded_keys() -> MyResult<()> {
+        let mut input = file_keys("a.txt", &[1, 2])?;
+        input.header = Some(crate::EfdHeader {
+            cnpj: "12345678000190".to_string(),
+            ..Default::default()
+        });
+        let formatada = input.chaves[0];
+        input.formatadas = vec![formatada];
+        input.urls = vec![ChaveUrl {
+            chave: input.chaves[1],
+            portal: "SP".to_string(),
+        }];
+
+        let mut memory = CollectSink::default();
+        let mut sink = MemoryDedupSink::new(&mut memory);
+        sink.write_file_keys(&input)?;
+        sink.finish()?;
+
+        let mut external = CollectSink::default();
+        let mut sink = ExternalDedupSink::new(&mut external, 1)?;
+        sink.write_file_keys(&input)?;
+        sink.finish()?;
+
+        assert_eq!(memory.files, [input.clone()]);
+        assert_eq!(external.files, [input]);
+        Ok(())
+    }
+
+    #[test]
+    fn structured_formats() -> MyResult<()> {
+        let input = file_keys("dir/PISCOFINS.txt", &[1, 2])?;
+
+        let mut text = TextSink::new(Vec::new());
+        text.write_file_keys(&input)?;
+        assert_eq!(
+            String::from_utf8_lossy(&text.into_inner()),
+            format!("{:044}\n{:044}\n", 1, 2)
+        );
+
+        let mut csv = CsvSink::new(Vec::new());
+        csv.write_file_keys(&input)?;
+        assert_eq!(
+            String::from_utf8_lossy(&csv.into_inner()?),
+            format!(
+                "chave,classe,ocorrencias,emissao,formatada,portal,registro,operacao,situacao,\
+                 participante,cnpj_participante,uf_participante,arquivo\n\
+                 {:044},desconhecida,1,,false,,,,,,,,dir/PISCOFINS.txt\n\
+                 {:044},desconhecida,1,,false,,,,,,,,dir/PISCOFINS.txt\n",
+                1, 2
+            )
+        );
+
+        let mut ndjson = NdjsonSink::new(Vec::new());
+        ndjson.write_file_keys(&input)?;
+        let first_line = String::from_utf8_lossy(&ndjson.into_inner())
+            .lines()
+            .next()
+            .map(str::to_string);
+        assert_eq!(
+            first_line,
+            Some(format!(
+                r#"{{"chave":"{:044}","classe":"desconhecida","ocorrencias":1,"emissao":null,"formatada":false,"portal":null,"registro":null,"operacao":null,"situacao":null,"participante":null,"cnpj_participante":null,"uf_participante":null,"arquivo":"dir/PISCOFINS.txt"}}"#,
+                1
+            ))
+        );
+        Ok(())
+    }
+}
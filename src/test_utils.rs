@@ -0,0 +1,7 @@
+use crate::{error::MyResult, Chave44};
+
+/// Completes the first 43 characters of a key with their check digit.
+pub fn chave_com_dv(sem_dv: &str) -> MyResult<Chave44> {
+    let dv = format!("{sem_dv}0").parse::<Chave44>()?.dv_calculado();
+    format!("{sem_dv}{dv}").parse()
+}
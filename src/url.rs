@@ -0,0 +1,114 @@
+use crate::{codigo_uf, Chave44};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+
+/// Portal of the national environment (Ambiente Nacional), e.g. `www.nfe.fazenda.gov.br`.
+pub const PORTAL_NACIONAL: &str = "AN";
+
+/// Lazy-initialized regex to find keys in NFC-e QR-code and SEFAZ query URLs.
+///
+/// Recognized shapes (the scheme is optional):
+/// - `https://<host>/...?p=<chave>|2|1|...` (QR code; the fields after the key
+///   are split by `split_line`, or encoded as `%7C`)
+/// - `https://<host>/...?chNFe=<chave>`, and likewise `chCTe`, `chMDFe`,
+///   `chave` and `chaveNFe`, in any position of the query string.
+pub static REGEX_URL_CHAVE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?ix)
+        (?:https?://)?
+        (?P<host>[a-z0-9-]+(?:\.[a-z0-9-]+)+)    # Host: at least two labels
+        (?::\d+)?                                # Optional port
+        (?:/[^\s?\#]*)?                          # Optional path
+        \?(?:[^\s\#]*&)?                         # Query string, up to the key parameter
+        (?:p|chNFe|chCTe|chMDFe|chave|chaveNFe)=
+        (?P<chave>\d{6}[0-9A-Z]{12}\d{26})       # The 44 characters of the key
+        (?:$|[^0-9A-Z])                          # Trailing end/non-alphanumeric
+    ",
+    )
+    .unwrap() // Regex compilation should not fail with a static string
+});
+
+/// A key found in a query URL, with the portal that published the URL.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ChaveUrl {
+    pub chave: Chave44,
+    /// See `portal_da_url`.
+    pub portal: String,
+}
+
+/// Identifies the SEFAZ portal of a URL host:
+/// - the state abbreviation for state portals (`www.fazenda.pr.gov.br` -> "PR"),
+/// - `PORTAL_NACIONAL` for the national portal (`www.nfe.fazenda.gov.br` -> "AN"),
+/// - the host itself (in lowercase) for any other site.
+pub fn portal_da_url(host: &str) -> String {
+    let host = host.to_ascii_lowercase();
+    let labels: Vec<&str> = host.split('.').collect();
+
+    match labels.as_slice() {
+        [.., uf, "gov", "br"] if uf.len() == 2 && codigo_uf(uf).is_some() => uf.to_uppercase(),
+        [.., "fazenda", "gov", "br"] => PORTAL_NACIONAL.to_string(),
+        _ => host,
+    }
+}
+
+/// Returns the keys found in the query URLs of a field.
+pub fn extract_url_keys(field: &str) -> impl Iterator<Item = ChaveUrl> + '_ {
+    REGEX_URL_CHAVE.captures_iter(field).filter_map(|capture| {
+        let chave = Chave44::from_bytes(capture.name("chave")?.as_str().as_bytes())?;
+        let portal = portal_da_url(capture.name("host")?.as_str());
+        Some(ChaveUrl { chave, portal })
+    })
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output url_tests
+#[cfg(test)]
+mod url_tests {
+    use super::*;
+    use crate::MyResult;
+
+    #[test]
+    fn keys_and_portals_of_query_urls() -> MyResult<()> {
+        let chave: Chave44 = "35250612345678000190550010000001231123456782".parse()?;
+        let found = |field: &str| extract_url_keys(field).collect::<Vec<_>>();
+        let url = |portal: &str| {
+            vec![ChaveUrl {
+                chave,
+                portal: portal.to_string(),
+            }]
+        };
+
+        assert_eq!(
+            found("QRCODE https://www.nfce.fazenda.sp.gov.br/qrcode?p=35250612345678000190550010000001231123456782"),
+            url("SP")
+        );
+        assert_eq!(
+            found("http://www.fazenda.pr.gov.br/nfce/qrcode?p=35250612345678000190550010000001231123456782%7C2%7C1%7C1%7CABC"),
+            url("PR")
+        );
+        assert_eq!(
+            found("www.nfe.fazenda.gov.br/portal/consultaRecaptcha.aspx?tipoConteudo=XbSeqxE8pl8=&chNFe=35250612345678000190550010000001231123456782"),
+            url("AN")
+        );
+        assert_eq!(
+            found("https://consulta.example.com:8080/nfe?chave=35250612345678000190550010000001231123456782&x=1"),
+            url("consulta.example.com")
+        );
+
+        // 45 digits, or no query parameter: not a key.
+        assert!(found(
+            "https://www.sefaz.rs.gov.br/nfce?p=352506123456780001905500100000012311234567821"
+        )
+        .is_empty());
+        assert!(found(
+            "https://www.sefaz.rs.gov.br/nfce/35250612345678000190550010000001231123456782"
+        )
+        .is_empty());
+        Ok(())
+    }
+}
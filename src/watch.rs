@@ -0,0 +1,161 @@
+use crate::{error::MyResult, split_line, FileStamp, NEWLINE_BYTE};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+use walkdir::DirEntry;
+
+/// Number of bytes read from the end of a file to look for the "9999" record.
+const TAIL_LEN: u64 = 4096;
+
+/// Detects EFD files that are new or were modified since they were processed.
+///
+/// A file may still be being written when it is found, so it is only reported
+/// as ready when it already contains the "9999" record (closing of the file),
+/// or when its size and modification time did not change between two polls.
+#[derive(Debug, Default)]
+pub struct EfdWatcher {
+    /// Stamp of each file when it was processed.
+    processed: HashMap<PathBuf, FileStamp>,
+    /// Stamp of each file waiting to become stable.
+    pending: HashMap<PathBuf, FileStamp>,
+}
+
+impl EfdWatcher {
+    /// Starts watching, considering `processed_entries` as already processed.
+    pub fn new(processed_entries: &[DirEntry]) -> MyResult<Self> {
+        let processed = processed_entries
+            .iter()
+            .map(|entry| Ok((entry.path().to_path_buf(), FileStamp::read(entry.path())?)))
+            .collect::<MyResult<_>>()?;
+
+        Ok(EfdWatcher {
+            processed,
+            pending: HashMap::new(),
+        })
+    }
+
+    /// Checks the current EFD file entries and returns those ready to be processed.
+    ///
+    /// Returned files are considered processed from now on.
+    pub fn poll(&mut self, entries: Vec<DirEntry>) -> MyResult<Vec<DirEntry>> {
+        let mut ready = Vec::new();
+
+        for entry in entries {
+            let path = entry.path();
+
+            // The file may have been removed or renamed since the directory was listed.
+            let Ok(stamp) = FileStamp::read(path) else {
+                continue;
+            };
+
+            if self.processed.get(path) == Some(&stamp) {
+                continue;
+            }
+
+            let stable = self.pending.get(path) == Some(&stamp);
+            // The tail may not be readable for the same reason, or while the producer
+            // holds the file: it then stays pending until the next poll.
+            let closed = || has_eof_marker(path).unwrap_or(false);
+
+            if stable || closed() {
+                self.pending.remove(path);
+                self.processed.insert(path.to_path_buf(), stamp);
+                ready.push(entry);
+            } else {
+                self.pending.insert(path.to_path_buf(), stamp);
+            }
+        }
+
+        Ok(ready)
+    }
+}
+
+/// Returns `true` if one of the last lines of the file is the "9999" record.
+pub fn has_eof_marker(path: &Path) -> MyResult<bool> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(TAIL_LEN)))?;
+
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+
+    let found = tail.split(|&byte| byte == NEWLINE_BYTE).any(|line| {
+        let line = String::from_utf8_lossy(line.trim_ascii());
+        split_line(line)
+            .first()
+            .is_some_and(|campo| campo == "9999")
+    });
+
+    Ok(found)
+}
+
+//----------------------------------------------------------------------------//
+//                                   Tests                                    //
+//----------------------------------------------------------------------------//
+
+/// Run tests with:
+/// cargo test -- --show-output watch_tests
+#[cfg(test)]
+mod watch_tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+    use walkdir::WalkDir;
+
+    fn entries(dir: &Path) -> Vec<DirEntry> {
+        WalkDir::new(dir)
+            .min_depth(1)
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn files_are_ready_when_closed_or_stable() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        let closed = temp_dir.path().join("PISCOFINS_CLOSED.txt");
+        let partial = temp_dir.path().join("PISCOFINS_PARTIAL.txt");
+
+        let mut watcher = EfdWatcher::new(&[])?;
+
+        fs::write(&closed, "|0000|006|\n|C100|1|\n|9999|3|\n")?;
+        fs::write(&partial, "|0000|006|\n|C100|1|\n")?;
+
+        // The closed file is ready at once; the partial one waits.
+        let ready = watcher.poll(entries(temp_dir.path()))?;
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].path(), closed);
+
+        // Nothing changed: the partial file is now stable.
+        let ready = watcher.poll(entries(temp_dir.path()))?;
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].path(), partial);
+
+        // Already processed files are not reported again.
+        assert!(watcher.poll(entries(temp_dir.path()))?.is_empty());
+
+        // A modified file is reported again.
+        fs::write(&closed, "|0000|006|\n|C100|1|\n|C100|2|\n|9999|4|\n")?;
+        let ready = watcher.poll(entries(temp_dir.path()))?;
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].path(), closed);
+        Ok(())
+    }
+
+    #[test]
+    fn unreadable_files_stay_pending() -> MyResult<()> {
+        let temp_dir = tempdir()?;
+        // Its stamp can be read, but not its content.
+        let unreadable = temp_dir.path().join("PISCOFINS_UNREADABLE.txt");
+        fs::create_dir(&unreadable)?;
+        assert!(has_eof_marker(&unreadable).is_err());
+
+        let mut watcher = EfdWatcher::new(&[])?;
+        assert!(watcher.poll(entries(temp_dir.path()))?.is_empty());
+        assert!(watcher.pending.contains_key(&unreadable));
+        Ok(())
+    }
+}